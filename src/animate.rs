@@ -0,0 +1,164 @@
+use anyhow::Result;
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+const CURSOR_HOME: &str = "\x1b[H";
+const CLEAR_SCREEN: &str = "\x1b[2J";
+const CLEAR_TO_END: &str = "\x1b[J";
+const CLEAR_LINE: &str = "\x1b[K";
+const HIDE_CURSOR: &str = "\x1b[?25l";
+const SHOW_CURSOR: &str = "\x1b[?25h";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Key {
+    TogglePause,
+    Step,
+    Quit,
+}
+
+impl Key {
+    fn parse(line: &str) -> Option<Self> {
+        match line.trim() {
+            "" | "p" => Some(Key::TogglePause),
+            "n" | "s" => Some(Key::Step),
+            "q" => Some(Key::Quit),
+            _ => None,
+        }
+    }
+}
+
+// Redraws a simulation in place in the terminal, one frame per step.
+//
+// stdin is line buffered, so each key is followed by <enter>:
+//
+//   <enter>  pause / resume
+//   n        pause and advance a single step
+//   q        stop animating
+pub struct Animation {
+    delay: Duration,
+    paused: bool,
+    quit: bool,
+    keys: Receiver<Key>,
+    num_frames: usize,
+}
+
+impl Animation {
+    pub fn new(delay: Duration) -> Self {
+        // Nothing can wait on stdin with a timeout, so read it from a
+        // background thread. The thread just dies with the process.
+        let (send, keys) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let key = match line {
+                    Ok(line) => Key::parse(&line),
+                    Err(_) => return,
+                };
+                if let Some(key) = key {
+                    if send.send(key).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self {
+            delay,
+            paused: false,
+            quit: false,
+            keys,
+            num_frames: 0,
+        }
+    }
+
+    // Draw the next frame under a title line, then wait for the delay to
+    // pass (or, while paused, for a key). Returns false once the user quits.
+    pub fn show(&mut self, title: &str, frame: &impl fmt::Display) -> Result<bool> {
+        if self.quit {
+            return Ok(false);
+        }
+
+        let mut screen = String::new();
+        if self.num_frames == 0 {
+            screen.push_str(HIDE_CURSOR);
+            screen.push_str(CLEAR_SCREEN);
+        }
+        screen.push_str(CURSOR_HOME);
+        screen.push_str(title);
+        screen.push_str(CLEAR_LINE);
+        screen.push('\n');
+        // Clear leftovers at the end of each line, in case the frame shrank.
+        for line in frame.to_string().lines() {
+            screen.push_str(line);
+            screen.push_str(CLEAR_LINE);
+            screen.push('\n');
+        }
+        let status = if self.paused { "paused" } else { "running" };
+        screen.push_str(&format!(
+            "\n{} ({} ms/step)  [enter] pause/resume  [n] step  [q] quit{}\n",
+            status,
+            self.delay.as_millis(),
+            CLEAR_TO_END,
+        ));
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()?;
+        self.num_frames += 1;
+
+        self.wait();
+        Ok(!self.quit)
+    }
+
+    fn wait(&mut self) {
+        loop {
+            let key = if self.paused {
+                match self.keys.recv() {
+                    Ok(key) => key,
+                    // stdin closed, nobody can unpause us
+                    Err(_) => Key::TogglePause,
+                }
+            } else {
+                match self.keys.recv_timeout(self.delay) {
+                    Ok(key) => key,
+                    Err(RecvTimeoutError::Timeout) => return,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        thread::sleep(self.delay);
+                        return;
+                    }
+                }
+            };
+
+            match key {
+                Key::TogglePause => {
+                    self.paused = !self.paused;
+                    if !self.paused {
+                        return;
+                    }
+                }
+                Key::Step => {
+                    self.paused = true;
+                    return;
+                }
+                Key::Quit => {
+                    self.quit = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Restore the cursor and leave the last frame on screen.
+    pub fn finish(self) -> Result<()> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        writeln!(stdout, "{}", SHOW_CURSOR)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,909 @@
+use crate::cycle::Simulation;
+use anyhow::{anyhow, Result};
+use fixedbitset::FixedBitSet;
+use itertools::Itertools;
+use ndarray::{ArrayD, ArrayViewD, ArrayViewMutD, Axis, IxDyn, Slice};
+use std::{mem, ops::Range, str::FromStr, thread};
+
+// Decides whether a cell is alive in the next generation, given whether it's
+// alive now and how many of its neighbors are alive.
+pub trait Rule: Sync {
+    fn next_state(&self, alive: bool, live_neighbors: usize) -> bool;
+}
+
+impl<F: Fn(bool, usize) -> bool + Sync> Rule for F {
+    fn next_state(&self, alive: bool, live_neighbors: usize) -> bool {
+        self(alive, live_neighbors)
+    }
+}
+
+// Life-like "B/S" rule: a dead cell is born if its live neighbor count is in
+// `birth`, and a live cell survives if its live neighbor count is in `survive`.
+#[derive(Clone, Debug)]
+pub struct LifeRule {
+    birth: FixedBitSet,
+    survive: FixedBitSet,
+}
+
+impl LifeRule {
+    pub fn new(birth: &[usize], survive: &[usize]) -> Self {
+        Self {
+            birth: birth.iter().copied().collect(),
+            survive: survive.iter().copied().collect(),
+        }
+    }
+
+    // Conway's Game of Life: B3/S23
+    pub fn conway() -> Self {
+        Self::new(&[3], &[2, 3])
+    }
+
+    // Parse a rule in "B3/S23" notation (single-digit neighbor counts).
+    pub fn parse(s: &str) -> Result<Self> {
+        let (birth, survive) = s
+            .split('/')
+            .collect_tuple()
+            .ok_or_else(|| anyhow!("expected 'B../S..': '{}'", s))?;
+
+        let parse_counts = |counts: &str, prefix: char| -> Result<Vec<usize>> {
+            let counts = counts
+                .strip_prefix(prefix)
+                .ok_or_else(|| anyhow!("expected '{}' prefix: '{}'", prefix, counts))?;
+            counts
+                .chars()
+                .map(|c| {
+                    c.to_digit(10)
+                        .map(|d| d as usize)
+                        .ok_or_else(|| anyhow!("invalid neighbor count: '{}'", c))
+                })
+                .collect()
+        };
+
+        Ok(Self::new(
+            &parse_counts(birth, 'B')?,
+            &parse_counts(survive, 'S')?,
+        ))
+    }
+}
+
+impl Rule for LifeRule {
+    #[inline]
+    fn next_state(&self, alive: bool, live_neighbors: usize) -> bool {
+        if alive {
+            self.survive.contains(live_neighbors)
+        } else {
+            self.birth.contains(live_neighbors)
+        }
+    }
+}
+
+// Which cells count as a cell's neighbors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Neighborhood {
+    // All cells within distance 1 along every axis (3^d - 1 neighbors).
+    Moore,
+    // All cells at distance 1 along exactly one axis (2d neighbors).
+    VonNeumann,
+    // The first maskable cell visible along each Moore direction, skipping
+    // over masked-out cells.
+    LineOfSight,
+}
+
+impl Neighborhood {
+    // Direction vectors to every neighbor on an unbounded square lattice, or
+    // None if the neighbors depend on the grid's contents (line of sight).
+    pub fn directions(self, ndim: usize) -> Option<Vec<Vec<isize>>> {
+        match self {
+            Neighborhood::LineOfSight => None,
+            _ => {
+                let [directions, _] = Lattice::Square.neighbor_directions(self, ndim);
+                Some(directions)
+            }
+        }
+    }
+}
+
+impl FromStr for Neighborhood {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "moore" => Ok(Self::Moore),
+            "von-neumann" => Ok(Self::VonNeumann),
+            "line-of-sight" => Ok(Self::LineOfSight),
+            _ => Err(anyhow!("unknown neighborhood: '{}'", s)),
+        }
+    }
+}
+
+// The shape of the cells tiling the grid. Hex and triangular lattices are
+// two-dimensional, but still stored as regular (rows, columns) arrays.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lattice {
+    // Squares, or (hyper)cubes in higher dimensions.
+    Square,
+    // Hexagons in axial coordinates: cell (r, q) touches (r, q ± 1),
+    // (r ± 1, q), (r - 1, q + 1), and (r + 1, q - 1).
+    Hex,
+    // Alternating triangles: cell (r, c) points up when r + c is even and
+    // down otherwise. Each row of triangles shares its edges with the row
+    // above (down triangles) or below (up triangles).
+    Triangular,
+}
+
+impl FromStr for Lattice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "square" => Ok(Self::Square),
+            "hex" => Ok(Self::Hex),
+            "triangular" => Ok(Self::Triangular),
+            _ => Err(anyhow!("unknown lattice: '{}'", s)),
+        }
+    }
+}
+
+const HEX_DIRECTIONS: [[isize; 2]; 6] = [[0, 1], [0, -1], [1, 0], [-1, 0], [-1, 1], [1, -1]];
+
+// Steps across each edge of an up triangle (left, right, bottom) and a down
+// triangle (left, right, top).
+const UP_TRIANGLE_EDGES: [[isize; 2]; 3] = [[0, -1], [0, 1], [1, 0]];
+const DOWN_TRIANGLE_EDGES: [[isize; 2]; 3] = [[0, -1], [0, 1], [-1, 0]];
+
+// A straight line through a triangular lattice alternates between crossing
+// two kinds of edges, so each ray is a pair of steps: one taken from up
+// triangles and one from down triangles. Horizontal rays stay in their row.
+const TRIANGLE_RAYS: [[[isize; 2]; 2]; 6] = [
+    [[0, 1], [0, 1]],
+    [[0, -1], [0, -1]],
+    [[0, 1], [-1, 0]],
+    [[1, 0], [0, -1]],
+    [[0, -1], [-1, 0]],
+    [[1, 0], [0, 1]],
+];
+
+impl Lattice {
+    // Direction vectors to the neighbors of cells with even and odd
+    // coordinate sums, respectively.
+    fn neighbor_directions(self, neighborhood: Neighborhood, ndim: usize) -> [Vec<Vec<isize>>; 2] {
+        let same = |directions: Vec<Vec<isize>>| [directions.clone(), directions];
+        match (self, neighborhood) {
+            (Lattice::Square, Neighborhood::VonNeumann) => same(
+                (0..ndim)
+                    .flat_map(|axis| {
+                        [-1, 1].iter().map(move |&d| {
+                            let mut direction = vec![0; ndim];
+                            direction[axis] = d;
+                            direction
+                        })
+                    })
+                    .collect(),
+            ),
+            (Lattice::Square, _) => same(moore_directions(ndim).collect()),
+            // Every neighbor of a hexagon shares an edge with it.
+            (Lattice::Hex, _) => same(HEX_DIRECTIONS.iter().map(|d| d.to_vec()).collect()),
+            (Lattice::Triangular, Neighborhood::VonNeumann) => [
+                UP_TRIANGLE_EDGES.iter().map(|d| d.to_vec()).collect(),
+                DOWN_TRIANGLE_EDGES.iter().map(|d| d.to_vec()).collect(),
+            ],
+            // The 12 triangles sharing at least a vertex: 5 in the row on
+            // the flat side, 4 in the same row, and 3 towards the point.
+            (Lattice::Triangular, _) => {
+                let vertex_neighbors = |flat_side: isize| {
+                    let mut directions = Vec::new();
+                    for dc in -2..=2 {
+                        directions.push(vec![flat_side, dc]);
+                        if dc != 0 {
+                            directions.push(vec![0, dc]);
+                        }
+                        if dc.abs() <= 1 {
+                            directions.push(vec![-flat_side, dc]);
+                        }
+                    }
+                    directions
+                };
+                [vertex_neighbors(1), vertex_neighbors(-1)]
+            }
+        }
+    }
+
+    // The rays followed by the line of sight neighborhood, as a pair of steps
+    // taken from cells with even and odd coordinate sums, respectively.
+    fn rays(self, ndim: usize) -> Vec<[Vec<isize>; 2]> {
+        match self {
+            Lattice::Square => moore_directions(ndim)
+                .map(|direction| [direction.clone(), direction])
+                .collect(),
+            Lattice::Hex => HEX_DIRECTIONS
+                .iter()
+                .map(|d| [d.to_vec(), d.to_vec()])
+                .collect(),
+            Lattice::Triangular => TRIANGLE_RAYS
+                .iter()
+                .map(|[even, odd]| [even.to_vec(), odd.to_vec()])
+                .collect(),
+        }
+    }
+}
+
+enum NeighborIndices {
+    // Relative flat offsets, valid for every cell thanks to the border.
+    Offsets(Vec<isize>),
+    // Explicit neighbor lists, one per maskable cell, stored contiguously:
+    // the neighbors of `cell_idxs[i]` are `idxs[starts[i]..starts[i + 1]]`.
+    Lists {
+        starts: Vec<usize>,
+        idxs: Vec<usize>,
+    },
+}
+
+// A cellular automaton over an N-dimensional grid of binary cells. The grid
+// is parameterized by a rule, a neighborhood, and a mask of cells that may
+// ever be alive (e.g., chairs but not floor tiles).
+pub struct Automaton<R> {
+    // Cell states, including a border of always-dead cells.
+    cells: ArrayD<u8>,
+    scratch: ArrayD<u8>,
+    // 1 for cells that may ever be alive, including a border of 0's.
+    mask: ArrayD<u8>,
+    // Flat indices of the cells in the mask.
+    cell_idxs: Vec<usize>,
+    neighbors: NeighborIndices,
+    rule: R,
+    num_threads: usize,
+    // Incremental stepping: the cells (as positions in `cell_idxs`) that
+    // might change in the next step, or None if every cell might.
+    frontier: Option<Vec<usize>>,
+    // For each cell, the cells that have it as a neighbor. Only built once we
+    // step incrementally.
+    dependents: Option<Dependents>,
+}
+
+// The transpose of the neighbor relation, as positions in `cell_idxs`:
+// the cells that see `cell_idxs[i]` are `positions[starts[i]..starts[i + 1]]`.
+struct Dependents {
+    starts: Vec<usize>,
+    positions: Vec<usize>,
+}
+
+// What a single incremental step did.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StepReport {
+    // Cells whose next state was computed
+    pub evaluated: usize,
+    // Cells whose state flipped
+    pub changed: usize,
+}
+
+impl<R: Rule> Automaton<R> {
+    // `initial` and `mask` have the same shape; if there's no mask then every
+    // cell may be alive.
+    pub fn new(
+        initial: ArrayViewD<'_, u8>,
+        mask: Option<ArrayViewD<'_, u8>>,
+        neighborhood: Neighborhood,
+        rule: R,
+    ) -> Self {
+        Self::with_lattice(initial, mask, Lattice::Square, neighborhood, rule)
+    }
+
+    pub fn with_lattice(
+        initial: ArrayViewD<'_, u8>,
+        mask: Option<ArrayViewD<'_, u8>>,
+        lattice: Lattice,
+        neighborhood: Neighborhood,
+        rule: R,
+    ) -> Self {
+        assert!(
+            lattice == Lattice::Square || initial.ndim() == 2,
+            "{:?} lattices are two-dimensional",
+            lattice
+        );
+
+        let padded_shape = initial
+            .shape()
+            .iter()
+            .map(|len| len + 2)
+            .collect::<Vec<_>>();
+
+        let mut padded_mask = ArrayD::zeros(IxDyn(&padded_shape));
+        match mask {
+            Some(mask) => interior_mut(&mut padded_mask).assign(&mask),
+            None => interior_mut(&mut padded_mask).fill(1),
+        }
+
+        let mut cells = ArrayD::zeros(IxDyn(&padded_shape));
+        interior_mut(&mut cells).assign(&initial);
+        cells *= &padded_mask;
+
+        let scratch = ArrayD::zeros(IxDyn(&padded_shape));
+
+        let cell_idxs = padded_mask
+            .as_slice()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &mask)| if mask == 1 { Some(idx) } else { None })
+            .collect::<Vec<_>>();
+
+        let ndim = cells.ndim();
+        let neighbors = match neighborhood {
+            Neighborhood::LineOfSight => {
+                line_of_sight_lists(&padded_mask, &cell_idxs, &lattice.rays(ndim))
+            }
+            _ => {
+                let [even, odd] = lattice.neighbor_directions(neighborhood, ndim);
+                // Uniform neighborhoods that fit in the border can skip the
+                // bounds checks and use the same flat offsets everywhere.
+                let fits_border = even.iter().flatten().all(|d| d.abs() <= 1);
+                if even == odd && fits_border {
+                    let offsets = even
+                        .iter()
+                        .map(|direction| flat_offset(direction, cells.strides()))
+                        .collect();
+                    NeighborIndices::Offsets(offsets)
+                } else {
+                    neighbor_lists(&padded_mask, &cell_idxs, &[even, odd])
+                }
+            }
+        };
+
+        Self {
+            cells,
+            scratch,
+            mask: padded_mask,
+            cell_idxs,
+            neighbors,
+            rule,
+            num_threads: 1,
+            frontier: None,
+            dependents: None,
+        }
+    }
+
+    // The current cell states, without the border.
+    pub fn cells(&self) -> ArrayViewD<'_, u8> {
+        interior(&self.cells)
+    }
+
+    // Which cells may ever be alive, without the border.
+    pub fn mask(&self) -> ArrayViewD<'_, u8> {
+        interior(&self.mask)
+    }
+
+    pub fn count_alive(&self) -> usize {
+        let cells = self.cells.as_slice().unwrap();
+        self.cell_idxs.iter().map(|&idx| cells[idx] as usize).sum()
+    }
+
+    // Split each step across this many threads. Results are identical to the
+    // single-threaded step.
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        self.num_threads = num_threads.max(1);
+    }
+
+    pub fn step(&mut self) {
+        // Double buffering: compute the next state into scratch, then swap.
+        let mut next = mem::take(&mut self.scratch);
+        let scratch = next.as_slice_mut().unwrap();
+
+        if self.num_threads > 1 {
+            self.step_parallel(scratch);
+        } else {
+            self.step_cells(0..self.cell_idxs.len(), scratch, 0);
+        }
+
+        self.scratch = mem::replace(&mut self.cells, next);
+        self.frontier = None;
+    }
+
+    // Compute the next state of `cell_idxs[range]` into `scratch`, which
+    // starts at flat index `scratch_offset`.
+    fn step_cells(&self, range: Range<usize>, scratch: &mut [u8], scratch_offset: usize) {
+        let cells = self.cells.as_slice().unwrap();
+        for i in range {
+            let idx = self.cell_idxs[i];
+            scratch[idx - scratch_offset] = self.next_state(cells, i) as u8;
+        }
+    }
+
+    fn next_state(&self, cells: &[u8], i: usize) -> bool {
+        let idx = self.cell_idxs[i];
+        let live_neighbors: usize = match &self.neighbors {
+            NeighborIndices::Offsets(offsets) => offsets
+                .iter()
+                .map(|&offset| cells[(idx as isize + offset) as usize] as usize)
+                .sum(),
+            NeighborIndices::Lists { starts, idxs } => idxs[starts[i]..starts[i + 1]]
+                .iter()
+                .map(|&neighbor_idx| cells[neighbor_idx] as usize)
+                .sum(),
+        };
+
+        let alive = cells[idx] == 1;
+        self.rule.next_state(alive, live_neighbors)
+    }
+
+    // Step only the cells that might change: a cell can only change if it or
+    // one of its neighbors changed in the previous step. Once most of the
+    // grid has settled, this costs time proportional to the remaining
+    // activity rather than the size of the grid. Always single-threaded, and
+    // gives the same results as `step`.
+    pub fn step_incremental(&mut self) -> StepReport {
+        if self.dependents.is_none() {
+            self.dependents = Some(self.build_dependents());
+        }
+        let frontier = self
+            .frontier
+            .take()
+            .unwrap_or_else(|| (0..self.cell_idxs.len()).collect());
+
+        // Compute every change against the current state before applying any.
+        let cells = self.cells.as_slice().unwrap();
+        let changed = frontier
+            .iter()
+            .copied()
+            .filter(|&i| self.next_state(cells, i) != (cells[self.cell_idxs[i]] == 1))
+            .collect::<Vec<_>>();
+
+        let cells = self.cells.as_slice_mut().unwrap();
+        for &i in &changed {
+            cells[self.cell_idxs[i]] ^= 1;
+        }
+
+        // Next step, look at the changed cells and everything that sees them.
+        let dependents = self.dependents.as_ref().unwrap();
+        let mut queued = FixedBitSet::with_capacity(self.cell_idxs.len());
+        for &i in &changed {
+            queued.insert(i);
+            queued.extend(
+                dependents.positions[dependents.starts[i]..dependents.starts[i + 1]]
+                    .iter()
+                    .copied(),
+            );
+        }
+        self.frontier = Some(queued.ones().collect());
+
+        StepReport {
+            evaluated: frontier.len(),
+            changed: changed.len(),
+        }
+    }
+
+    fn build_dependents(&self) -> Dependents {
+        // Map flat indices back to positions in `cell_idxs`.
+        let mut positions = vec![usize::MAX; self.cells.len()];
+        for (i, &idx) in self.cell_idxs.iter().enumerate() {
+            positions[idx] = i;
+        }
+
+        // (neighbor, cell) pairs, grouped by neighbor
+        let mut edges = Vec::new();
+        for (i, &idx) in self.cell_idxs.iter().enumerate() {
+            match &self.neighbors {
+                NeighborIndices::Offsets(offsets) => {
+                    for &offset in offsets {
+                        let neighbor = positions[(idx as isize + offset) as usize];
+                        if neighbor != usize::MAX {
+                            edges.push((neighbor, i));
+                        }
+                    }
+                }
+                NeighborIndices::Lists { starts, idxs } => {
+                    for &neighbor_idx in &idxs[starts[i]..starts[i + 1]] {
+                        edges.push((positions[neighbor_idx], i));
+                    }
+                }
+            }
+        }
+        edges.sort_unstable();
+
+        let mut starts = Vec::with_capacity(self.cell_idxs.len() + 1);
+        let mut edge_idx = 0;
+        for i in 0..self.cell_idxs.len() {
+            starts.push(edge_idx);
+            while edge_idx < edges.len() && edges[edge_idx].0 == i {
+                edge_idx += 1;
+            }
+        }
+        starts.push(edges.len());
+
+        Dependents {
+            starts,
+            positions: edges.into_iter().map(|(_, cell)| cell).collect(),
+        }
+    }
+
+    // Split the cells into contiguous runs of whole hyperplanes along the
+    // outermost axis, one per thread. Each thread reads the shared current
+    // state and writes its own disjoint slice of the scratch buffer.
+    fn step_parallel(&self, mut scratch: &mut [u8]) {
+        let num_cells = self.cell_idxs.len();
+        let hyperplane_len = self.cells.strides()[0] as usize;
+
+        let mut chunk_starts = (0..self.num_threads)
+            .map(|thread_idx| {
+                let target = thread_idx * num_cells / self.num_threads;
+                if target == 0 || target == num_cells {
+                    return target;
+                }
+                // Snap back to the first cell of the target's hyperplane.
+                let hyperplane_start = self.cell_idxs[target] / hyperplane_len * hyperplane_len;
+                self.cell_idxs[..target].partition_point(|&idx| idx < hyperplane_start)
+            })
+            .collect::<Vec<_>>();
+        chunk_starts.push(num_cells);
+        chunk_starts.dedup();
+
+        thread::scope(|scope| {
+            let mut scratch_offset = 0;
+            for bounds in chunk_starts.windows(2) {
+                let range = bounds[0]..bounds[1];
+                // Everything before the next chunk's first cell belongs to us.
+                let split_idx = if bounds[1] == num_cells {
+                    scratch.len()
+                } else {
+                    self.cell_idxs[bounds[1]] - scratch_offset
+                };
+                let (chunk_scratch, rest) = mem::take(&mut scratch).split_at_mut(split_idx);
+                let chunk_offset = scratch_offset;
+                scope.spawn(move || self.step_cells(range, chunk_scratch, chunk_offset));
+
+                scratch = rest;
+                scratch_offset += split_idx;
+            }
+        });
+    }
+}
+
+// The state of an automaton is which of its maskable cells are alive. States
+// are compared exactly (no hashing), so cycle detection can't be fooled by
+// collisions.
+impl<R: Rule> Simulation for Automaton<R> {
+    type State = FixedBitSet;
+
+    fn state(&self) -> FixedBitSet {
+        let cells = self.cells.as_slice().unwrap();
+        let mut state = FixedBitSet::with_capacity(self.cell_idxs.len());
+        for (i, &idx) in self.cell_idxs.iter().enumerate() {
+            state.set(i, cells[idx] == 1);
+        }
+        state
+    }
+
+    fn set_state(&mut self, state: &FixedBitSet) {
+        let cells = self.cells.as_slice_mut().unwrap();
+        for (i, &idx) in self.cell_idxs.iter().enumerate() {
+            cells[idx] = state.contains(i) as u8;
+        }
+        self.frontier = None;
+    }
+
+    fn step(&mut self) {
+        Automaton::step(self);
+    }
+}
+
+fn interior<A>(array: &ArrayD<A>) -> ArrayViewD<'_, A> {
+    let mut view = array.view();
+    for axis in 0..view.ndim() {
+        view.slice_axis_inplace(Axis(axis), Slice::new(1, Some(-1), 1));
+    }
+    view
+}
+
+fn interior_mut<A>(array: &mut ArrayD<A>) -> ArrayViewMutD<'_, A> {
+    let mut view = array.view_mut();
+    for axis in 0..view.ndim() {
+        view.slice_axis_inplace(Axis(axis), Slice::new(1, Some(-1), 1));
+    }
+    view
+}
+
+// All non-zero direction vectors in {-1, 0, 1}^ndim.
+fn moore_directions(ndim: usize) -> impl Iterator<Item = Vec<isize>> {
+    let num_directions = 3_usize.pow(ndim as u32);
+    (0..num_directions)
+        .map(move |mut n| {
+            (0..ndim)
+                .map(|_| {
+                    let d = (n % 3) as isize - 1;
+                    n /= 3;
+                    d
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|direction| direction.iter().any(|&d| d != 0))
+}
+
+fn flat_offset(direction: &[isize], strides: &[isize]) -> isize {
+    direction
+        .iter()
+        .zip(strides)
+        .map(|(d, stride)| d * stride)
+        .sum()
+}
+
+fn in_interior(coords: &[isize], shape: &[usize]) -> bool {
+    coords
+        .iter()
+        .zip(shape)
+        .all(|(&coord, &len)| 0 < coord && coord < len as isize - 1)
+}
+
+// Whether the cell at these (padded) coordinates has an odd coordinate sum,
+// which picks the directions on a triangular lattice.
+fn parity(coords: &[isize]) -> usize {
+    coords
+        .iter()
+        .map(|coord| coord - 1)
+        .sum::<isize>()
+        .rem_euclid(2) as usize
+}
+
+// For each maskable cell, list the maskable cells at the given directions,
+// chosen by the cell's parity.
+fn neighbor_lists(
+    mask: &ArrayD<u8>,
+    cell_idxs: &[usize],
+    directions: &[Vec<Vec<isize>>; 2],
+) -> NeighborIndices {
+    let shape = mask.shape();
+    let strides = mask.strides();
+    let mask = mask.as_slice().unwrap();
+
+    let mut starts = Vec::with_capacity(cell_idxs.len() + 1);
+    let mut idxs = Vec::new();
+
+    for &cell_idx in cell_idxs {
+        starts.push(idxs.len());
+
+        let coords = unravel_index(cell_idx, shape);
+        for direction in &directions[parity(&coords)] {
+            let neighbor = coords
+                .iter()
+                .zip(direction)
+                .map(|(coord, d)| coord + d)
+                .collect::<Vec<_>>();
+            if !in_interior(&neighbor, shape) {
+                continue;
+            }
+
+            let idx = (cell_idx as isize + flat_offset(direction, strides)) as usize;
+            if mask[idx] == 1 {
+                idxs.push(idx);
+            }
+        }
+    }
+    starts.push(idxs.len());
+
+    NeighborIndices::Lists { starts, idxs }
+}
+
+// For each maskable cell, walk along each ray until we either see another
+// maskable cell (a neighbor) or hit the border. Each step along a ray depends
+// on the parity of the cell we're stepping from.
+fn line_of_sight_lists(
+    mask: &ArrayD<u8>,
+    cell_idxs: &[usize],
+    rays: &[[Vec<isize>; 2]],
+) -> NeighborIndices {
+    let shape = mask.shape();
+    let strides = mask.strides();
+    let mask = mask.as_slice().unwrap();
+
+    let mut starts = Vec::with_capacity(cell_idxs.len() + 1);
+    let mut idxs = Vec::new();
+
+    for &cell_idx in cell_idxs {
+        starts.push(idxs.len());
+
+        for ray in rays {
+            let mut coords = unravel_index(cell_idx, shape);
+            let mut idx = cell_idx as isize;
+
+            loop {
+                let direction = &ray[parity(&coords)];
+                for (coord, d) in coords.iter_mut().zip(direction) {
+                    *coord += d;
+                }
+                if !in_interior(&coords, shape) {
+                    break;
+                }
+
+                idx += flat_offset(direction, strides);
+                if mask[idx as usize] == 1 {
+                    idxs.push(idx as usize);
+                    break;
+                }
+            }
+        }
+
+        // Neighboring rays on a triangular lattice can leave through the same
+        // edge, but each visible cell only counts once.
+        let mut visible = idxs.split_off(*starts.last().unwrap());
+        visible.sort_unstable();
+        visible.dedup();
+        idxs.extend(visible);
+    }
+    starts.push(idxs.len());
+
+    NeighborIndices::Lists { starts, idxs }
+}
+
+fn unravel_index(mut idx: usize, shape: &[usize]) -> Vec<isize> {
+    let mut coords = vec![0; shape.len()];
+    for (coord, &len) in coords.iter_mut().zip(shape).rev() {
+        *coord = (idx % len) as isize;
+        idx /= len;
+    }
+    coords
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::Rng;
+    use ndarray::Dimension;
+
+    fn random_grid(rng: &mut Rng, shape: &[usize], density: usize) -> ArrayD<u8> {
+        ArrayD::from_shape_fn(IxDyn(shape), |_| (rng.below(100) < density) as u8)
+    }
+
+    #[test]
+    fn test_parallel_step() {
+        let mut rng = Rng::new(0xdead_beef_cafe_f00d);
+        let shapes: [&[usize]; 4] = [&[1, 1], &[13, 37], &[5, 9, 11], &[4, 5, 6, 7]];
+
+        for &shape in &shapes {
+            let initial = random_grid(&mut rng, shape, 30);
+            let mask = random_grid(&mut rng, shape, 80);
+
+            for &neighborhood in &[
+                Neighborhood::Moore,
+                Neighborhood::VonNeumann,
+                Neighborhood::LineOfSight,
+            ] {
+                let new = || {
+                    Automaton::new(
+                        initial.view(),
+                        Some(mask.view()),
+                        neighborhood,
+                        LifeRule::new(&[2, 3], &[1, 2, 3, 4]),
+                    )
+                };
+                for num_threads in 2..6 {
+                    let mut sequential = new();
+                    let mut parallel = new();
+                    parallel.set_num_threads(num_threads);
+                    for _ in 0..5 {
+                        assert_eq!(parallel.cells(), sequential.cells());
+                        assert_eq!(parallel.state(), sequential.state());
+                        parallel.step();
+                        sequential.step();
+                    }
+                }
+            }
+        }
+    }
+
+    // Step a full grid of live cells once with a rule that keeps exactly the
+    // cells with `num_neighbors` live neighbors.
+    fn cells_with_neighbors(
+        lattice: Lattice,
+        neighborhood: Neighborhood,
+        mask: &ArrayD<u8>,
+        num_neighbors: usize,
+    ) -> Vec<Vec<isize>> {
+        let mut automaton = Automaton::with_lattice(
+            mask.view(),
+            Some(mask.view()),
+            lattice,
+            neighborhood,
+            move |_alive, live_neighbors| live_neighbors == num_neighbors,
+        );
+        automaton.step();
+        automaton
+            .cells()
+            .indexed_iter()
+            .filter(|(_, &alive)| alive == 1)
+            .map(|(idx, _)| idx.as_array_view().iter().map(|&i| i as isize).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_lattices() {
+        let full = |shape: &[usize]| ArrayD::from_elem(IxDyn(shape), 1_u8);
+
+        // Only the hexagons away from the edges have all 6 neighbors.
+        let expected = (1..4)
+            .flat_map(|r| (1..4).map(move |q| vec![r, q]))
+            .collect::<Vec<_>>();
+        for &neighborhood in &[Neighborhood::Moore, Neighborhood::VonNeumann] {
+            let cells = cells_with_neighbors(Lattice::Hex, neighborhood, &full(&[5, 5]), 6);
+            assert_eq!(cells, expected);
+        }
+
+        // Up triangles need a row below and down triangles a row above.
+        let cells = cells_with_neighbors(
+            Lattice::Triangular,
+            Neighborhood::VonNeumann,
+            &full(&[3, 4]),
+            3,
+        );
+        assert_eq!(cells, vec![vec![0, 2], vec![1, 1], vec![1, 2], vec![2, 1]]);
+
+        // Only the middle of a 3x5 grid touches 12 triangles.
+        let cells =
+            cells_with_neighbors(Lattice::Triangular, Neighborhood::Moore, &full(&[3, 5]), 12);
+        assert_eq!(cells, vec![vec![1, 2]]);
+
+        // The two seats in a row can see each other across the gap, and no
+        // other ray sees either seat twice.
+        let mut row = full(&[1, 4]);
+        row[[0, 1]] = 0;
+        row[[0, 2]] = 0;
+        let cells = cells_with_neighbors(Lattice::Triangular, Neighborhood::LineOfSight, &row, 1);
+        assert_eq!(cells, vec![vec![0, 0], vec![0, 3]]);
+
+        let cells = cells_with_neighbors(
+            Lattice::Triangular,
+            Neighborhood::LineOfSight,
+            &full(&[3, 4]),
+            3,
+        );
+        assert_eq!(cells, vec![vec![0, 2], vec![1, 1], vec![1, 2], vec![2, 1]]);
+    }
+
+    #[test]
+    fn test_step_incremental() {
+        let mut rng = Rng::new(0x0123_4567_89ab_cdef);
+        let cases: [(&[usize], Lattice); 5] = [
+            (&[13, 37], Lattice::Square),
+            (&[5, 9, 11], Lattice::Square),
+            (&[4, 5, 6, 7], Lattice::Square),
+            (&[17, 23], Lattice::Hex),
+            (&[17, 23], Lattice::Triangular),
+        ];
+
+        for &(shape, lattice) in &cases {
+            let initial = random_grid(&mut rng, shape, 30);
+            let mask = random_grid(&mut rng, shape, 80);
+
+            for &neighborhood in &[
+                Neighborhood::Moore,
+                Neighborhood::VonNeumann,
+                Neighborhood::LineOfSight,
+            ] {
+                let new = || {
+                    Automaton::with_lattice(
+                        initial.view(),
+                        Some(mask.view()),
+                        lattice,
+                        neighborhood,
+                        LifeRule::new(&[0, 2], &[0, 1, 2, 3]),
+                    )
+                };
+                let mut full = new();
+                let mut incremental = new();
+                for step in 0..20 {
+                    let before = full.state();
+                    full.step();
+                    let report = incremental.step_incremental();
+                    assert_eq!(incremental.state(), full.state());
+
+                    let mut flipped = before.clone();
+                    flipped.symmetric_difference_with(&full.state());
+                    assert_eq!(report.changed, flipped.count_ones(..));
+
+                    // Restoring a state forgets the frontier.
+                    if step == 10 {
+                        full.set_state(&before);
+                        incremental.set_state(&before);
+                    }
+                }
+            }
+        }
+    }
+}
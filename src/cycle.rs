@@ -0,0 +1,131 @@
+// A deterministic simulation whose full state can be saved and restored, so
+// we can search for cycles in its trajectory.
+pub trait Simulation {
+    type State: Clone + Eq;
+
+    fn state(&self) -> Self::State;
+    fn set_state(&mut self, state: &Self::State);
+    fn step(&mut self);
+}
+
+// The trajectory x_0, x_1, .. of a simulation eventually enters a cycle:
+// x_{start + length} = x_start, where `start` and `length` are minimal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cycle {
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Cycle {
+    // The simulation stops changing after `start` steps.
+    pub const fn is_fixed_point(&self) -> bool {
+        self.length == 1
+    }
+}
+
+// Find the cycle in the simulation's trajectory using Brent's algorithm,
+// comparing full states rather than hashes. Only O(1) states are kept around.
+//
+// Leaves the simulation at the start of the cycle, x_start.
+pub fn find_cycle<S: Simulation>(sim: &mut S) -> Cycle {
+    let x0 = sim.state();
+
+    // Phase 1: find the cycle length by racing the hare (`sim`) against a
+    // tortoise that teleports to the hare at every power of two.
+    let mut power = 1;
+    let mut length = 1;
+    let mut tortoise = x0.clone();
+    sim.step();
+
+    while sim.state() != tortoise {
+        if power == length {
+            tortoise = sim.state();
+            power *= 2;
+            length = 0;
+        }
+        sim.step();
+        length += 1;
+    }
+
+    // Phase 2: find the cycle start by walking the tortoise from x_0 and the
+    // hare from x_length in lockstep until they meet.
+    sim.set_state(&x0);
+    for _ in 0..length {
+        sim.step();
+    }
+    let mut hare = sim.state();
+    let mut tortoise = x0;
+    let mut start = 0;
+
+    while tortoise != hare {
+        sim.set_state(&tortoise);
+        sim.step();
+        tortoise = sim.state();
+
+        sim.set_state(&hare);
+        sim.step();
+        hare = sim.state();
+
+        start += 1;
+    }
+
+    sim.set_state(&tortoise);
+    Cycle { start, length }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // x -> x^2 + 1 mod m
+    struct Quadratic {
+        x: u64,
+        m: u64,
+    }
+
+    impl Simulation for Quadratic {
+        type State = u64;
+
+        fn state(&self) -> u64 {
+            self.x
+        }
+
+        fn set_state(&mut self, state: &u64) {
+            self.x = *state;
+        }
+
+        fn step(&mut self) {
+            self.x = (self.x * self.x + 1) % self.m;
+        }
+    }
+
+    // Find the cycle by remembering every state.
+    fn find_cycle_naive(mut sim: Quadratic) -> Cycle {
+        let mut history = vec![sim.state()];
+        loop {
+            sim.step();
+            if let Some(start) = history.iter().position(|&x| x == sim.state()) {
+                let length = history.len() - start;
+                return Cycle { start, length };
+            }
+            history.push(sim.state());
+        }
+    }
+
+    #[test]
+    fn test_find_cycle() {
+        for m in 1..200 {
+            for x in 0..m {
+                let mut sim = Quadratic { x, m };
+                let cycle = find_cycle(&mut sim);
+                assert_eq!(cycle, find_cycle_naive(Quadratic { x, m }));
+
+                let x_start = sim.state();
+                for _ in 0..cycle.length {
+                    sim.step();
+                }
+                assert_eq!(sim.state(), x_start);
+            }
+        }
+    }
+}
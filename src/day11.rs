@@ -1,355 +1,1011 @@
-#![allow(clippy::reversed_empty_ranges)]
-
-use anyhow::{Context, Result};
-use arrayvec::ArrayVec;
-use fixedbitset::FixedBitSet;
-use ndarray::{azip, s, Array, Array2};
-use std::{
-    collections::hash_map::DefaultHasher,
-    fmt, fs,
-    hash::{Hash, Hasher},
-    iter::{self, FromIterator},
-    mem, str,
+use crate::{
+    animate::Animation,
+    automaton::{Automaton, Lattice, LifeRule, Neighborhood, Rule, StepReport},
+    cycle::{find_cycle, Cycle, Simulation},
+    frames::{Frame, FrameRecorder, Rgb},
 };
+use anyhow::{anyhow, Context, Result};
+use fixedbitset::FixedBitSet;
+use ndarray::{Array, Array2, Ix2};
+use std::{fmt, fs, iter::FromIterator, mem, time::Duration};
 
-#[derive(Debug)]
+// A seating layout: chairs are cells that may be occupied, floor tiles are
+// masked out and never change.
 struct Layout {
-    occupied: Array2<u8>,
-    floor_mask: Array2<u8>,
-    scratch: Array2<u8>,
+    seats: Automaton<LifeRule>,
+    lattice: Lattice,
+    // Number of seats per row in the input. Hex layouts are stored skewed in
+    // axial coordinates, so this can be less than the stored width.
+    ncols: usize,
+}
+
+// Square and triangular layouts are plain grids of characters, one per cell.
+fn parse_grid(input: &str) -> Array2<u8> {
+    let mut n: usize = 0;
+    let mut m: usize = 0;
+    let elem_iter = input.lines().flat_map(|line| {
+        n += 1;
+        m = line.len();
+        line.chars().map(|c| match c {
+            'L' => 1,
+            '.' => 0,
+            _ => panic!("unexpected char: {}", c),
+        })
+    });
+    Array::from_iter(elem_iter).into_shape((n, m)).unwrap()
+}
+
+// Hex layouts are written in "odd-r" offset rows, with cells separated by
+// spaces and every odd row shifted right by half a cell:
+//
+//   L . L L
+//    L L . L
+//   L L L .
+//
+// Offset row r, column c is stored at axial coordinates (r, c - r / 2),
+// shifted right so that every column index is non-negative.
+fn parse_hex(input: &str) -> Array2<u8> {
+    let rows = input
+        .lines()
+        .map(|line| {
+            line.chars()
+                .filter(|c| !c.is_whitespace())
+                .map(|c| match c {
+                    'L' => 1,
+                    '.' => 0,
+                    _ => panic!("unexpected char: {}", c),
+                })
+                .collect::<Vec<u8>>()
+        })
+        .collect::<Vec<_>>();
+
+    let n = rows.len();
+    let m = rows.first().map(Vec::len).unwrap_or(0);
+    let mut mask = Array2::zeros((n, m + hex_shift(n)));
+    for (r, row) in rows.iter().enumerate() {
+        assert_eq!(row.len(), m, "hex rows must all have {} seats", m);
+        for (c, &seat) in row.iter().enumerate() {
+            mask[(r, hex_column(r, c, n))] = seat;
+        }
+    }
+    mask
+}
+
+fn hex_shift(nrows: usize) -> usize {
+    nrows.saturating_sub(1) / 2
+}
+
+// The stored column of offset row r, column c.
+fn hex_column(r: usize, c: usize, nrows: usize) -> usize {
+    c + hex_shift(nrows) - r / 2
 }
 
 impl Layout {
-    fn from_str(input: &str) -> Self {
-        let mut n: usize = 0;
-        let mut m: usize = 0;
-        let elem_iter = input.lines().flat_map(|line| {
-            n += 1;
-            m = line.len();
-            line.chars().map(|c| match c {
-                'L' => 1,
-                '.' => 0,
-                _ => panic!("unexpected char: {}", c),
-            })
-        });
-        // include border of 0's
-        let floor_mask = Array::from_iter(elem_iter).into_shape((n, m)).unwrap();
+    fn from_str(input: &str, lattice: Lattice, neighborhood: Neighborhood, rule: LifeRule) -> Self {
+        let floor_mask = match lattice {
+            Lattice::Hex => parse_hex(input),
+            Lattice::Square | Lattice::Triangular => parse_grid(input),
+        };
+        let (n, m) = floor_mask.dim();
+        let ncols = match lattice {
+            Lattice::Hex => m - hex_shift(n),
+            Lattice::Square | Lattice::Triangular => m,
+        };
 
-        // include border of 0's
         // initial layout is all empty
-        let occupied = Array2::zeros(((n + 2), (m + 2)));
-        let scratch = Array2::zeros((n, m));
+        let occupied = Array2::zeros((n, m));
+
+        let seats = Automaton::with_lattice(
+            occupied.into_dyn().view(),
+            Some(floor_mask.into_dyn().view()),
+            lattice,
+            neighborhood,
+            rule,
+        );
 
         Self {
-            occupied,
-            floor_mask,
-            scratch,
+            seats,
+            lattice,
+            ncols,
         }
     }
 
-    fn nrows(&self) -> usize {
-        self.floor_mask.nrows()
+    // If a seat is empty and there are no occupied adjacent seats, it becomes occupied.
+    // If a seat is occupied and 4 or more adjacent seats are occupied, it becomes empty.
+    fn from_str_v1(input: &str, lattice: Lattice) -> Self {
+        Self::from_str(
+            input,
+            lattice,
+            Neighborhood::Moore,
+            LifeRule::new(&[0], &[0, 1, 2, 3]),
+        )
     }
 
-    fn ncols(&self) -> usize {
-        self.floor_mask.ncols()
+    // Same as v1, except we look at the first visible seat in each direction
+    // and it takes 5 or more occupied visible seats to empty a seat.
+    fn from_str_v2(input: &str, lattice: Lattice) -> Self {
+        Self::from_str(
+            input,
+            lattice,
+            Neighborhood::LineOfSight,
+            LifeRule::new(&[0], &[0, 1, 2, 3, 4]),
+        )
     }
 
-    fn hash(&self) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        self.occupied.hash(&mut hasher);
-        hasher.finish()
+    fn count_occupied(&self) -> usize {
+        self.seats.count_alive()
     }
 
-    fn count_occupied(&self) -> u16 {
-        let occupied = self.occupied.as_slice_memory_order().unwrap();
-        occupied.iter().map(|&val| val as u16).sum()
+    fn step(&mut self) {
+        self.seats.step();
     }
 
-    fn step(&mut self) {
-        let mut neigh = self.scratch.view_mut();
-        neigh.fill(0);
+    fn set_num_threads(&mut self, num_threads: usize) {
+        self.seats.set_num_threads(num_threads);
+    }
 
-        // di = -1, dj = {-1, 0, 1}
-        neigh += &self.occupied.slice(s![0..-2, 0..-2]);
-        neigh += &self.occupied.slice(s![0..-2, 1..-1]);
-        neigh += &self.occupied.slice(s![0..-2, 2..]);
+    fn step_incremental(&mut self) -> StepReport {
+        self.seats.step_incremental()
+    }
 
-        // di = 0, dj = {-1, 1}
-        neigh += &self.occupied.slice(s![1..-1, 0..-2]);
-        neigh += &self.occupied.slice(s![1..-1, 2..]);
+    // The FLOOR / EMPTY / OCCUPIED tiles of each row, in input order.
+    fn tile_rows(&self) -> Vec<Vec<u8>> {
+        let occupied = self.seats.cells().into_dimensionality::<Ix2>().unwrap();
+        let floor_mask = self.seats.mask().into_dimensionality::<Ix2>().unwrap();
+        let nrows = occupied.nrows();
+
+        (0..nrows)
+            .map(|r| {
+                (0..self.ncols)
+                    .map(|c| {
+                        let idx = match self.lattice {
+                            Lattice::Hex => (r, hex_column(r, c, nrows)),
+                            Lattice::Square | Lattice::Triangular => (r, c),
+                        };
+                        if floor_mask[idx] == 0 {
+                            FLOOR
+                        } else if occupied[idx] == 1 {
+                            OCCUPIED
+                        } else {
+                            EMPTY
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // One pixel per cell, colored by SEAT_PALETTE. Hex cells are two pixels
+    // wide so that odd rows can be shifted by half a cell.
+    fn render(&self) -> Frame {
+        let rows = self.tile_rows();
+        let cell_width = if self.lattice == Lattice::Hex { 2 } else { 1 };
+        let width = self.ncols * cell_width + cell_width - 1;
+
+        let mut frame = Frame::new(width, rows.len(), FLOOR);
+        for (r, row) in rows.iter().enumerate() {
+            let shift = if self.lattice == Lattice::Hex {
+                r % 2
+            } else {
+                0
+            };
+            for (c, &tile) in row.iter().enumerate() {
+                for dx in 0..cell_width {
+                    frame.set(c * cell_width + shift + dx, r, tile);
+                }
+            }
+        }
+        frame
+    }
+}
 
-        // di = 1, dj = {-1, 0, 1}
-        neigh += &self.occupied.slice(s![2.., 0..-2]);
-        neigh += &self.occupied.slice(s![2.., 1..-1]);
-        neigh += &self.occupied.slice(s![2.., 2..]);
+const FLOOR: u8 = 0;
+const EMPTY: u8 = 1;
+const OCCUPIED: u8 = 2;
+const SEAT_PALETTE: [Rgb; 3] = [[40, 40, 40], [70, 170, 90], [210, 60, 50]];
 
-        let mut occupied = self.occupied.slice_mut(s![1..-1, 1..-1]);
+impl Simulation for Layout {
+    type State = FixedBitSet;
 
-        // If a seat is empty and there are no occupied neighbors, it becomes occupied.
-        // If a seat is occupied and that are 4 or more neighbors, it becomes unoccupied.
-        occupied.zip_mut_with(&neigh, |o, &n| {
-            *o = ((*o == 0 && n == 0) || (*o == 1 && n < 4)) as u8
-        });
+    fn state(&self) -> FixedBitSet {
+        self.seats.state()
+    }
 
-        // Floor tiles should remain floor tiles
-        occupied *= &self.floor_mask;
+    fn set_state(&mut self, state: &FixedBitSet) {
+        self.seats.set_state(state);
+    }
+
+    fn step(&mut self) {
+        Layout::step(self);
     }
 }
 
+// Hex layouts are printed in the same offset-row format they're parsed from.
 impl fmt::Display for Layout {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let nrows = self.nrows();
-        let ncols = self.ncols();
-
-        let mut str_buf = String::with_capacity(ncols + 1);
-
-        let occupied_view = self.occupied.slice(s![1..-1, 1..-1]);
-        azip!((index (i, j), &occupied in &occupied_view, &mask in &self.floor_mask) {
-            if mask == 0 {
-                str_buf.push('.');
-            } else if occupied == 1 {
-                str_buf.push('#');
-            } else {
-                str_buf.push('L');
-            }
-
-            if j == ncols - 1 {
-                if i != nrows - 1 {
-                    str_buf.push('\n');
-                }
-                let _ = f.write_str(&str_buf);
-                str_buf.clear();
-            }
-        });
+        let sep = if self.lattice == Lattice::Hex {
+            " "
+        } else {
+            ""
+        };
+
+        let lines = self
+            .tile_rows()
+            .iter()
+            .enumerate()
+            .map(|(r, row)| {
+                let indent = if !sep.is_empty() && r % 2 == 1 {
+                    " "
+                } else {
+                    ""
+                };
+                let tiles = row
+                    .iter()
+                    .map(|&tile| match tile {
+                        FLOOR => ".",
+                        EMPTY => "L",
+                        _ => "#",
+                    })
+                    .collect::<Vec<_>>();
+                format!("{}{}", indent, tiles.join(sep))
+            })
+            .collect::<Vec<_>>();
 
-        Ok(())
+        f.write_str(&lines.join("\n"))
     }
 }
 
-const DIRECTIONS: [(i8, i8); 8] = [
-    (-1, -1),
-    (-1, 0),
-    (-1, 1),
-    (0, -1),
-    (0, 1),
-    (1, -1),
-    (1, 0),
-    (1, 1),
-];
-
-struct Layout2 {
+// Seat layout stored as bitboards: one bit per cell, packed into u64 words
+// row by row, so each step updates 64 seats at a time. Neighbor counts are
+// accumulated with bit-sliced adders: bit plane k holds bit k of every seat's
+// count.
+struct BitLayout {
     nrows: usize,
     ncols: usize,
-    occupied: FixedBitSet,
-    floor_mask: FixedBitSet,
-    scratch: FixedBitSet,
-    neighbor_indices: Vec<ArrayVec<[usize; 8]>>,
+    words_per_row: usize,
+    chairs: Vec<u64>,
+    // Cells inside the grid that aren't chairs.
+    floor: Vec<u64>,
+    occupied: Vec<u64>,
+    scratch: Vec<u64>,
+    directions: Vec<(isize, isize)>,
+    line_of_sight: bool,
+    // Rule lookup tables, indexed by number of occupied neighbors.
+    next_if_occupied: [bool; 9],
+    next_if_empty: [bool; 9],
 }
 
-impl Layout2 {
-    #[inline]
-    const fn conv_1d_to_2d(ncols: usize, idx_1d: usize) -> (usize, usize) {
-        let row_idx = idx_1d / ncols;
-        let col_idx = idx_1d % ncols;
-        (row_idx, col_idx)
-    }
-
-    #[inline]
-    const fn conv_2d_to_1d(ncols: usize, row_idx: usize, col_idx: usize) -> usize {
-        row_idx * ncols + col_idx
-    }
-
-    fn indices_in_direction(
-        nrows: usize,
-        ncols: usize,
-        row_idx: usize,
-        col_idx: usize,
-        dr: i8,
-        dc: i8,
-    ) -> impl Iterator<Item = (usize, usize)> {
-        let nrows = nrows as isize;
-        let ncols = ncols as isize;
-        let dr = dr as isize;
-        let dc = dc as isize;
-        iter::successors(Some((row_idx, col_idx)), move |(row_idx, col_idx)| {
-            let new_row_idx = *row_idx as isize + dr;
-            let new_col_idx = *col_idx as isize + dc;
-
-            if 0 <= new_row_idx && new_row_idx < nrows && 0 <= new_col_idx && new_col_idx < ncols {
-                Some((new_row_idx as usize, new_col_idx as usize))
-            } else {
-                None
-            }
-        })
-        .skip(1)
-    }
-
-    fn build_neighbor_indices(
-        floor_mask: &FixedBitSet,
-        nrows: usize,
-        ncols: usize,
-    ) -> Vec<ArrayVec<[usize; 8]>> {
-        let mut neighbor_indices = Vec::with_capacity(floor_mask.count_ones(..));
-
-        for chair_idx in floor_mask.ones() {
-            let (row_idx, col_idx) = Self::conv_1d_to_2d(ncols, chair_idx);
-
-            let mut idxs = ArrayVec::new();
-            for (dr, dc) in DIRECTIONS.iter().copied() {
-                for (i, j) in Self::indices_in_direction(nrows, ncols, row_idx, col_idx, dr, dc) {
-                    let idx = Self::conv_2d_to_1d(ncols, i, j);
-                    if floor_mask.contains(idx) {
-                        idxs.push(idx);
-                        break;
-                    }
+impl BitLayout {
+    fn from_str(input: &str, neighborhood: Neighborhood, rule: LifeRule) -> Self {
+        let nrows = input.lines().count();
+        let ncols = input.lines().next().map_or(0, str::len);
+        // usize::div_ceil needs Rust 1.73
+        #[allow(clippy::manual_div_ceil)]
+        let words_per_row = (ncols + 63) / 64;
+
+        let mut chairs = vec![0_u64; nrows * words_per_row];
+        let mut floor = vec![0_u64; nrows * words_per_row];
+        for (row_idx, line) in input.lines().enumerate() {
+            for (col_idx, c) in line.chars().enumerate() {
+                let word_idx = row_idx * words_per_row + col_idx / 64;
+                let bit = 1 << (col_idx % 64);
+                match c {
+                    'L' => chairs[word_idx] |= bit,
+                    '.' => floor[word_idx] |= bit,
+                    _ => panic!("unexpected char: {}", c),
                 }
             }
-
-            neighbor_indices.push(idxs);
         }
 
-        neighbor_indices
-    }
-
-    fn from_str(input: &str) -> Self {
-        let mut nrows: usize = 0;
-        let mut ncols: usize = 0;
-        let elem_iter = input.lines().flat_map(|line| {
-            nrows += 1;
-            ncols = line.len();
-            line.chars().map(|c| match c {
-                'L' => true,
-                '.' => false,
-                _ => panic!("unexpected char: {}", c),
-            })
-        });
-
-        let chair_idxs = elem_iter.enumerate().filter_map(
-            |(idx, is_chair)| {
-                if is_chair {
-                    Some(idx)
-                } else {
-                    None
-                }
-            },
-        );
-
-        let floor_mask = FixedBitSet::from_iter(chair_idxs);
-        let occupied = FixedBitSet::with_capacity(floor_mask.len());
-        let scratch = FixedBitSet::with_capacity(floor_mask.len());
-        let neighbor_indices = Self::build_neighbor_indices(&floor_mask, nrows, ncols);
+        let directions = match neighborhood {
+            Neighborhood::Moore | Neighborhood::LineOfSight => vec![
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+            Neighborhood::VonNeumann => vec![(-1, 0), (0, -1), (0, 1), (1, 0)],
+        };
+
+        let mut next_if_occupied = [false; 9];
+        let mut next_if_empty = [false; 9];
+        for n in 0..9 {
+            next_if_occupied[n] = rule.next_state(true, n);
+            next_if_empty[n] = rule.next_state(false, n);
+        }
 
         Self {
             nrows,
             ncols,
-            occupied,
-            floor_mask,
-            scratch,
-            neighbor_indices,
+            words_per_row,
+            occupied: vec![0; chairs.len()],
+            scratch: vec![0; chairs.len()],
+            chairs,
+            floor,
+            directions,
+            line_of_sight: neighborhood == Neighborhood::LineOfSight,
+            next_if_occupied,
+            next_if_empty,
         }
     }
 
-    fn hash(&self) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        self.occupied.hash(&mut hasher);
-        hasher.finish()
+    fn from_str_v1(input: &str) -> Self {
+        Self::from_str(
+            input,
+            Neighborhood::Moore,
+            LifeRule::new(&[0], &[0, 1, 2, 3]),
+        )
+    }
+
+    fn from_str_v2(input: &str) -> Self {
+        Self::from_str(
+            input,
+            Neighborhood::LineOfSight,
+            LifeRule::new(&[0], &[0, 1, 2, 3, 4]),
+        )
+    }
+
+    fn row<'a>(&self, board: &'a [u64], row_idx: usize) -> &'a [u64] {
+        &board[row_idx * self.words_per_row..(row_idx + 1) * self.words_per_row]
     }
 
     fn count_occupied(&self) -> usize {
-        self.occupied.count_ones(..)
+        self.occupied
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    // Compute the board of cells whose neighbor in direction (dr, dc) is
+    // occupied. With line of sight, the neighbor is the first chair in that
+    // direction, so occupied seats are seen through any number of floor tiles.
+    fn visible(&self, dr: isize, dc: isize) -> Vec<u64> {
+        let wpr = self.words_per_row;
+        let mut visible = vec![0_u64; self.occupied.len()];
+        let mut seen = vec![0_u64; wpr];
+
+        if dr == 0 {
+            for row_idx in 0..self.nrows {
+                let occupied = self.row(&self.occupied, row_idx);
+                let floor = self.row(&self.floor, row_idx);
+                let out = &mut visible[row_idx * wpr..(row_idx + 1) * wpr];
+
+                // seen[c] := the first chair at or beyond c going in direction
+                // dc is occupied.
+                if !self.line_of_sight {
+                    seen.copy_from_slice(occupied);
+                } else if dc > 0 {
+                    fill_toward_lower(occupied, floor, &mut seen);
+                } else {
+                    fill_toward_higher(occupied, floor, &mut seen);
+                }
+                shift_cols(&seen, dc, out);
+            }
+        } else {
+            // Walk rows starting from the edge we're looking towards, since
+            // each row's view depends on the view of the row after it.
+            let row_idxs: Box<dyn Iterator<Item = usize>> = if dr > 0 {
+                Box::new((0..self.nrows).rev())
+            } else {
+                Box::new(0..self.nrows)
+            };
+
+            for row_idx in row_idxs {
+                let src_idx = row_idx as isize + dr;
+                if src_idx < 0 || src_idx >= self.nrows as isize {
+                    continue;
+                }
+                let src_idx = src_idx as usize;
+
+                let occupied = self.row(&self.occupied, src_idx);
+                let floor = self.row(&self.floor, src_idx);
+                let src_visible = &visible[src_idx * wpr..(src_idx + 1) * wpr];
+
+                for word_idx in 0..wpr {
+                    seen[word_idx] = if self.line_of_sight {
+                        occupied[word_idx] | (floor[word_idx] & src_visible[word_idx])
+                    } else {
+                        occupied[word_idx]
+                    };
+                }
+                shift_cols(&seen, dc, &mut visible[row_idx * wpr..(row_idx + 1) * wpr]);
+            }
+        }
+
+        visible
     }
 
     fn step(&mut self) {
-        self.scratch.clear();
-
-        for (idx, chair_idx) in self.floor_mask.ones().enumerate() {
-            let neighbors = &self.neighbor_indices[idx];
-            let num_neighbors: usize = neighbors
-                .iter()
-                .map(|&neighbor_idx| self.occupied.contains(neighbor_idx) as usize)
-                .sum();
-            let is_occupied = self.occupied.contains(chair_idx);
-            let is_now_occupied =
-                is_occupied && num_neighbors < 5 || !is_occupied && num_neighbors == 0;
-
-            self.scratch.set(chair_idx, is_now_occupied);
+        // 4 bit planes are enough to count up to 8 neighbors.
+        let mut planes = vec![vec![0_u64; self.occupied.len()]; 4];
+
+        for &(dr, dc) in &self.directions {
+            let visible = self.visible(dr, dc);
+            for (word_idx, &word) in visible.iter().enumerate() {
+                // Ripple-carry add one bit into every seat's count.
+                let mut carry = word;
+                for plane in &mut planes {
+                    let sum = plane[word_idx] ^ carry;
+                    carry &= plane[word_idx];
+                    plane[word_idx] = sum;
+                }
+            }
+        }
+
+        for word_idx in 0..self.occupied.len() {
+            let mut survive = 0;
+            let mut birth = 0;
+            for n in 0..9 {
+                if !self.next_if_occupied[n] && !self.next_if_empty[n] {
+                    continue;
+                }
+                // Mask of seats with exactly n occupied neighbors
+                let count_n = planes.iter().enumerate().fold(!0, |mask, (k, plane)| {
+                    if (n >> k) & 1 == 1 {
+                        mask & plane[word_idx]
+                    } else {
+                        mask & !plane[word_idx]
+                    }
+                });
+                if self.next_if_occupied[n] {
+                    survive |= count_n;
+                }
+                if self.next_if_empty[n] {
+                    birth |= count_n;
+                }
+            }
+
+            let occupied = self.occupied[word_idx];
+            self.scratch[word_idx] =
+                self.chairs[word_idx] & ((occupied & survive) | (!occupied & birth));
         }
 
         mem::swap(&mut self.occupied, &mut self.scratch);
     }
 }
 
-impl fmt::Display for Layout2 {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut str_buf = String::with_capacity(self.ncols + 1);
+// out[c] := row[c + dc], for dc in {-1, 0, 1}, treating columns outside the
+// row as 0.
+fn shift_cols(row: &[u64], dc: isize, out: &mut [u64]) {
+    let num_words = row.len();
+    for word_idx in 0..num_words {
+        out[word_idx] = match dc {
+            0 => row[word_idx],
+            1 => {
+                let next = if word_idx + 1 < num_words {
+                    row[word_idx + 1] << 63
+                } else {
+                    0
+                };
+                (row[word_idx] >> 1) | next
+            }
+            -1 => {
+                let prev = if word_idx > 0 {
+                    row[word_idx - 1] >> 63
+                } else {
+                    0
+                };
+                (row[word_idx] << 1) | prev
+            }
+            _ => panic!("unexpected dc: {}", dc),
+        };
+    }
+}
+
+// out[c] := occupied[c] | (floor[c] & out[c + 1]), i.e., occupied seats
+// propagate towards lower columns through floor tiles. Uses a Kogge-Stone
+// occluded fill within each word, carrying between words.
+fn fill_toward_lower(occupied: &[u64], floor: &[u64], out: &mut [u64]) {
+    let mut carry = 0_u64;
+    for word_idx in (0..occupied.len()).rev() {
+        let mut pro = floor[word_idx];
+        let mut gen = occupied[word_idx] | (pro & (carry << 63));
+        for shift in &[1, 2, 4, 8, 16, 32] {
+            gen |= pro & (gen >> shift);
+            pro &= pro >> shift;
+        }
+        out[word_idx] = gen;
+        carry = gen & 1;
+    }
+}
+
+// out[c] := occupied[c] | (floor[c] & out[c - 1])
+fn fill_toward_higher(occupied: &[u64], floor: &[u64], out: &mut [u64]) {
+    let mut carry = 0_u64;
+    for word_idx in 0..occupied.len() {
+        let mut pro = floor[word_idx];
+        let mut gen = occupied[word_idx] | (pro & carry);
+        for shift in &[1, 2, 4, 8, 16, 32] {
+            gen |= pro & (gen << shift);
+            pro &= pro << shift;
+        }
+        out[word_idx] = gen;
+        carry = gen >> 63;
+    }
+}
+
+impl BitLayout {
+    // One pixel per cell, colored by SEAT_PALETTE, same as `Layout::render`.
+    fn render(&self) -> Frame {
+        let mut frame = Frame::new(self.ncols, self.nrows, FLOOR);
         for row_idx in 0..self.nrows {
-            str_buf.clear();
+            let chairs = self.row(&self.chairs, row_idx);
+            let occupied = self.row(&self.occupied, row_idx);
             for col_idx in 0..self.ncols {
-                let idx = Self::conv_2d_to_1d(self.ncols, row_idx, col_idx);
-                if !self.floor_mask.contains(idx) {
-                    str_buf.push('.');
-                } else if self.occupied.contains(idx) {
-                    str_buf.push('#');
-                } else {
-                    str_buf.push('L');
+                let bit = 1 << (col_idx % 64);
+                if chairs[col_idx / 64] & bit != 0 {
+                    let tile = if occupied[col_idx / 64] & bit != 0 {
+                        OCCUPIED
+                    } else {
+                        EMPTY
+                    };
+                    frame.set(col_idx, row_idx, tile);
                 }
             }
-            str_buf.push('\n');
-            f.write_str(&str_buf)?;
+        }
+        frame
+    }
+}
+
+impl fmt::Display for BitLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row_idx in 0..self.nrows {
+            if row_idx != 0 {
+                f.write_str("\n")?;
+            }
+            let chairs = self.row(&self.chairs, row_idx);
+            let occupied = self.row(&self.occupied, row_idx);
+            let row_str = (0..self.ncols)
+                .map(|col_idx| {
+                    let bit = 1 << (col_idx % 64);
+                    if chairs[col_idx / 64] & bit == 0 {
+                        '.'
+                    } else if occupied[col_idx / 64] & bit != 0 {
+                        '#'
+                    } else {
+                        'L'
+                    }
+                })
+                .collect::<String>();
+            f.write_str(&row_str)?;
         }
         Ok(())
     }
 }
 
-fn part1(input: &str) {
-    let mut layout = Layout::from_str(input);
-    let mut iter = 0;
-    let mut hash = layout.hash();
+impl Simulation for BitLayout {
+    type State = Vec<u64>;
 
-    loop {
-        layout.step();
-        iter += 1;
+    fn state(&self) -> Vec<u64> {
+        self.occupied.clone()
+    }
 
-        let next_hash = layout.hash();
-        if next_hash == hash {
-            break;
-        }
+    fn set_state(&mut self, state: &Vec<u64>) {
+        self.occupied.copy_from_slice(state);
+    }
+
+    fn step(&mut self) {
+        BitLayout::step(self);
+    }
+}
 
-        hash = next_hash;
+// Give up on reaching a fixed point incrementally after this many steps.
+const MAX_INCREMENTAL_STEPS: usize = 10_000;
+
+// Step until the layout stops changing, only re-evaluating the seats around
+// last step's changes, and print how many seats changed at each step. Falls
+// back to full cycle detection if the layout never settles.
+fn settle_incremental(layout: &mut Layout) -> Cycle {
+    let initial = layout.state();
+    let num_seats = initial.len();
+
+    let mut changed_per_step = Vec::new();
+    let mut evaluated = 0;
+    while changed_per_step.len() < MAX_INCREMENTAL_STEPS {
+        let report = layout.step_incremental();
+        evaluated += report.evaluated;
+        if report.changed == 0 {
+            let num_steps = changed_per_step.len();
+            println!("fixed point after {} steps", num_steps);
+            println!("changed per step: {:?}", changed_per_step);
+            println!(
+                "evaluated {} seats, vs {} for full steps",
+                evaluated,
+                num_seats * (num_steps + 1)
+            );
+            return Cycle {
+                start: num_steps,
+                length: 1,
+            };
+        }
+        changed_per_step.push(report.changed);
     }
 
-    dbg!(iter);
-    dbg!(layout.count_occupied());
+    println!(
+        "no fixed point after {} steps, falling back to cycle detection",
+        MAX_INCREMENTAL_STEPS
+    );
+    layout.set_state(&initial);
+    simulate(layout)
 }
 
-fn part2(input: &str) {
-    let mut layout = Layout2::from_str(input);
-    let mut iter = 0;
-    let mut hash = layout.hash();
+fn simulate<S: Simulation>(layout: &mut S) -> Cycle {
+    let cycle = find_cycle(layout);
 
-    loop {
+    if cycle.is_fixed_point() {
+        println!("fixed point after {} steps", cycle.start);
+    } else {
+        println!(
+            "period-{} oscillation starting at step {}",
+            cycle.length, cycle.start
+        );
+    }
+
+    cycle
+}
+
+// Record every step from the initial layout through one full cycle.
+fn record_frames<S: Simulation>(
+    layout: &mut S,
+    initial: &S::State,
+    cycle: Cycle,
+    name: &str,
+    run_opts: &RunOptions,
+    render: impl Fn(&S) -> Frame,
+) -> Result<()> {
+    let dir = match &run_opts.frames_dir {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+
+    let mut recorder = FrameRecorder::new(dir, name, &SEAT_PALETTE, run_opts.scale, run_opts.gif)?;
+    layout.set_state(initial);
+    recorder.record(&render(layout))?;
+    for _ in 0..cycle.start + cycle.length {
         layout.step();
-        iter += 1;
+        recorder.record(&render(layout))?;
+    }
+    recorder.finish()
+}
 
-        let next_hash = layout.hash();
-        if next_hash == hash {
+// Replay the layout from its initial state through one full cycle in the
+// terminal. Stops early if the user quits.
+fn animate<S: Simulation + fmt::Display>(
+    layout: &mut S,
+    initial: &S::State,
+    cycle: Cycle,
+    title: &str,
+    animation: &mut Animation,
+) -> Result<()> {
+    let num_steps = cycle.start + cycle.length;
+    layout.set_state(initial);
+    for step in 0..=num_steps {
+        let title = format!("{}: step {}/{}", title, step, num_steps);
+        if !animation.show(&title, layout)? {
             break;
         }
+        layout.step();
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+struct RunOptions {
+    lattice: Lattice,
+    bitboard: bool,
+    // Only re-evaluate seats next to last step's changes
+    incremental: bool,
+    num_threads: usize,
+    // Write a PPM image of every step into this directory
+    frames_dir: Option<String>,
+    // Also assemble the frames into an animated GIF
+    gif: bool,
+    scale: usize,
+    // Redraw every step in the terminal, waiting `delay` in between
+    animate: bool,
+    delay: Duration,
+}
+
+// Record and animate a settled layout, if asked to.
+fn replay<S: Simulation + fmt::Display>(
+    layout: &mut S,
+    initial: &S::State,
+    cycle: Cycle,
+    label: &str,
+    run_opts: &RunOptions,
+    render: impl Fn(&S) -> Frame,
+    animation: Option<&mut Animation>,
+) -> Result<()> {
+    record_frames(layout, initial, cycle, label, run_opts, render)?;
+    if let Some(animation) = animation {
+        animate(layout, initial, cycle, label, animation)?;
+    }
+    Ok(())
+}
 
-        hash = next_hash;
+fn run_part(
+    label: &str,
+    input: &str,
+    bit_layout: fn(&str) -> BitLayout,
+    layout: fn(&str, Lattice) -> Layout,
+    run_opts: &RunOptions,
+    animation: Option<&mut Animation>,
+) -> Result<()> {
+    if run_opts.bitboard {
+        let mut layout = bit_layout(input);
+        let initial = layout.state();
+        let cycle = simulate(&mut layout);
+        dbg!(layout.count_occupied());
+        replay(
+            &mut layout,
+            &initial,
+            cycle,
+            label,
+            run_opts,
+            BitLayout::render,
+            animation,
+        )
+    } else {
+        let mut layout = layout(input, run_opts.lattice);
+        layout.set_num_threads(run_opts.num_threads);
+        let initial = layout.state();
+        let cycle = if run_opts.incremental {
+            settle_incremental(&mut layout)
+        } else {
+            simulate(&mut layout)
+        };
+        dbg!(layout.count_occupied());
+        replay(
+            &mut layout,
+            &initial,
+            cycle,
+            label,
+            run_opts,
+            Layout::render,
+            animation,
+        )
     }
+}
+
+fn part1(input: &str, run_opts: &RunOptions, animation: Option<&mut Animation>) -> Result<()> {
+    run_part(
+        "part1",
+        input,
+        BitLayout::from_str_v1,
+        Layout::from_str_v1,
+        run_opts,
+        animation,
+    )
+}
 
-    dbg!(iter);
-    dbg!(layout.count_occupied());
+fn part2(input: &str, run_opts: &RunOptions, animation: Option<&mut Animation>) -> Result<()> {
+    run_part(
+        "part2",
+        input,
+        BitLayout::from_str_v2,
+        Layout::from_str_v2,
+        run_opts,
+        animation,
+    )
 }
 
+// usage: day11 <input> [--lattice square|hex|triangular] [--bitboard | --incremental]
+//                      [--threads N] [--frames DIR [--gif] [--scale N]]
+//                      [--animate [--delay MS]]
 pub fn run(args: &[&str]) -> Result<()> {
     let input = fs::read_to_string(args[0]).context("Failed to read file")?;
 
-    time!(part1(&input));
+    let mut run_opts = RunOptions {
+        lattice: Lattice::Square,
+        bitboard: false,
+        incremental: false,
+        num_threads: 1,
+        frames_dir: None,
+        gif: false,
+        scale: 4,
+        animate: false,
+        delay: Duration::from_millis(100),
+    };
+
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        match *flag {
+            "--lattice" => run_opts.lattice = flags.next().context("Missing lattice")?.parse()?,
+            "--bitboard" => run_opts.bitboard = true,
+            "--incremental" => run_opts.incremental = true,
+            "--threads" => {
+                let num_threads = flags.next().context("Missing number of threads")?;
+                run_opts.num_threads = num_threads.parse().context("Invalid number of threads")?;
+            }
+            "--frames" => {
+                let dir = flags.next().context("Missing frames directory")?;
+                run_opts.frames_dir = Some(dir.to_string());
+            }
+            "--gif" => run_opts.gif = true,
+            "--scale" => {
+                let scale = flags.next().context("Missing scale")?;
+                run_opts.scale = scale.parse().context("Invalid scale")?;
+            }
+            "--animate" => run_opts.animate = true,
+            "--delay" => {
+                let ms = flags.next().context("Missing delay")?;
+                run_opts.delay = Duration::from_millis(ms.parse().context("Invalid delay")?);
+            }
+            _ => return Err(anyhow!("unexpected arg: '{}'", flag)),
+        }
+    }
+    if run_opts.bitboard && run_opts.lattice != Lattice::Square {
+        return Err(anyhow!("--bitboard only supports square lattices"));
+    }
+    if run_opts.bitboard && run_opts.incremental {
+        return Err(anyhow!("--incremental doesn't support --bitboard"));
+    }
+
+    let mut animation = if run_opts.animate {
+        Some(Animation::new(run_opts.delay))
+    } else {
+        None
+    };
 
-    time!(part2(&input));
+    time!(part1(&input, &run_opts, animation.as_mut()))?;
+
+    time!(part2(&input, &run_opts, animation.as_mut()))?;
+
+    if let Some(animation) = animation {
+        animation.finish()?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::Rng;
+
+    const EXAMPLE: &str = "\
+        L.LL.LL.LL\n\
+        LLLLLLL.LL\n\
+        L.L.L..L..\n\
+        LLLL.LL.LL\n\
+        L.LL.LL.LL\n\
+        L.LLLLL.LL\n\
+        ..L.L.....\n\
+        LLLLLLLLLL\n\
+        L.LLLLLL.L\n\
+        L.LLLLL.LL\
+    ";
+
+    #[test]
+    fn test_example() {
+        let mut layout = Layout::from_str_v1(EXAMPLE, Lattice::Square);
+        layout.step();
+        layout.step();
+        assert_eq!(
+            layout.to_string(),
+            "\
+            #.LL.L#.##\n\
+            #LLLLLL.L#\n\
+            L.L.L..L..\n\
+            #LLL.LL.L#\n\
+            #.LL.LL.LL\n\
+            #.LLLL#.##\n\
+            ..L.L.....\n\
+            #LLLLLLLL#\n\
+            #.LLLLLL.L\n\
+            #.#LLLL.##\
+            "
+        );
+
+        let mut layout = Layout::from_str_v1(EXAMPLE, Lattice::Square);
+        let cycle = find_cycle(&mut layout);
+        assert_eq!(
+            cycle,
+            Cycle {
+                start: 5,
+                length: 1
+            }
+        );
+        assert_eq!(layout.count_occupied(), 37);
+
+        let mut layout = Layout::from_str_v2(EXAMPLE, Lattice::Square);
+        let cycle = find_cycle(&mut layout);
+        assert_eq!(
+            cycle,
+            Cycle {
+                start: 6,
+                length: 1
+            }
+        );
+        assert_eq!(layout.count_occupied(), 26);
+    }
+
+    fn random_layout(rng: &mut Rng, nrows: usize, ncols: usize) -> String {
+        (0..nrows)
+            .map(|_| {
+                (0..ncols)
+                    .map(|_| if rng.below(3) == 0 { '.' } else { 'L' })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_bitboard() {
+        let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+        let sizes = [
+            (1, 1),
+            (3, 2),
+            (10, 10),
+            (7, 63),
+            (5, 64),
+            (9, 65),
+            (4, 130),
+            (40, 200),
+        ];
+
+        for &(nrows, ncols) in &sizes {
+            let input = random_layout(&mut rng, nrows, ncols);
+
+            for &neighborhood in &[
+                Neighborhood::Moore,
+                Neighborhood::VonNeumann,
+                Neighborhood::LineOfSight,
+            ] {
+                let rule = LifeRule::new(&[0], &[0, 1, 2, 3]);
+                let mut layout =
+                    Layout::from_str(&input, Lattice::Square, neighborhood, rule.clone());
+                let mut bit_layout = BitLayout::from_str(&input, neighborhood, rule);
+
+                for _ in 0..20 {
+                    assert_eq!(bit_layout.to_string(), layout.to_string());
+                    assert_eq!(bit_layout.render(), layout.render());
+                    layout.step();
+                    bit_layout.step();
+                }
+            }
+        }
+
+        let mut layout = BitLayout::from_str_v1(EXAMPLE);
+        assert_eq!(
+            find_cycle(&mut layout),
+            Cycle {
+                start: 5,
+                length: 1
+            }
+        );
+        assert_eq!(layout.count_occupied(), 37);
+
+        let mut layout = BitLayout::from_str_v2(EXAMPLE);
+        assert_eq!(
+            find_cycle(&mut layout),
+            Cycle {
+                start: 6,
+                length: 1
+            }
+        );
+        assert_eq!(layout.count_occupied(), 26);
+    }
+
+    #[test]
+    fn test_hex() {
+        let input = "L L L\n L L L\nL L L";
+        let mut layout = Layout::from_str_v1(input, Lattice::Hex);
+        assert_eq!(layout.to_string(), "L L L\n L L L\nL L L");
+
+        // Seats with 4 or more of their (up to 6) neighbors empty out.
+        layout.step();
+        layout.step();
+        assert_eq!(layout.to_string(), "# L #\n L L #\n# L #");
+
+        let mut layout = Layout::from_str_v2("L . . L\n . L . .", Lattice::Hex);
+        layout.step();
+        assert_eq!(layout.to_string(), "# . . #\n . # . .");
+        assert_eq!(layout.seats.cells().shape(), &[2, 4]);
+    }
+}
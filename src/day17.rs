@@ -1,12 +1,24 @@
-#![allow(clippy::reversed_empty_ranges)]
+use crate::{
+    animate::Animation,
+    automaton::{Automaton, LifeRule, Neighborhood, Rule},
+    frames::{Frame, FrameRecorder, Rgb},
+};
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use ndarray::{Array, Array2, ArrayD, ArrayViewD, Axis, IxDyn, Slice as AxisSlice};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    hash::{BuildHasherDefault, Hasher},
+    iter::FromIterator,
+    mem,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
-use ndarray::{s, Array, Array2, Array3, Array4, Slice};
-use std::{fs, iter::FromIterator};
-
-const BORDER_SIZE: usize = 1;
-const MAX_ITERS: usize = 6;
-const I: isize = BORDER_SIZE as isize + MAX_ITERS as isize;
+// How many cells of room to add around every edge when the active region
+// reaches the edge of the grid.
+const GROW_BY: usize = 2;
 
 fn parse_input(input: &str) -> Array2<u8> {
     let mut x_len: usize = 0;
@@ -26,143 +38,1271 @@ fn parse_input(input: &str) -> Array2<u8> {
     z_0.into_shape((x_len, y_len)).unwrap()
 }
 
-#[derive(Debug)]
+fn check_rule(ndim: usize, rule: &LifeRule) -> Result<()> {
+    if ndim < 2 {
+        return Err(anyhow!("need at least 2 dimensions, got {}", ndim));
+    }
+    // Every inactive cube out to infinity would become active.
+    if rule.next_state(false, 0) {
+        return Err(anyhow!("rules with B0 activate all of infinite space"));
+    }
+    Ok(())
+}
+
+// Conway cubes in `ndim` dimensions: the input is the (x, y) slice at the
+// origin of the remaining `ndim - 2` dimensions. The grid starts out with a
+// margin of inactive cubes around the input and grows whenever the active
+// region reaches its edge.
 struct Cubes {
-    active: Array3<u8>,
-    scratch: Array3<u8>,
+    active: Automaton<LifeRule>,
+    neighborhood: Neighborhood,
+    rule: LifeRule,
+    num_threads: usize,
+    // The index of the origin along each axis, which may be outside the grid
+    origin: Vec<isize>,
 }
 
 impl Cubes {
-    fn new(z0: &Array2<u8>) -> Self {
+    fn new(
+        z0: &Array2<u8>,
+        ndim: usize,
+        margin: usize,
+        neighborhood: Neighborhood,
+        rule: LifeRule,
+    ) -> Result<Self> {
+        check_rule(ndim, &rule)?;
+
         let (x_len, y_len) = z0.dim();
+        let mut shape = vec![margin + 1 + margin; ndim - 2];
+        shape.push(margin + x_len + margin);
+        shape.push(margin + y_len + margin);
+
+        let mut initial = ArrayD::zeros(IxDyn(&shape));
+        let mut slice = initial.view_mut();
+        for _ in 0..ndim - 2 {
+            slice = slice.index_axis_move(Axis(0), margin);
+        }
+        for axis in 0..2 {
+            let len = slice.len_of(Axis(axis));
+            slice.slice_axis_inplace(Axis(axis), AxisSlice::from(margin..len - margin));
+        }
+        slice.assign(z0);
+
+        Ok(Self::from_cells(
+            initial,
+            vec![margin as isize; ndim],
+            neighborhood,
+            rule,
+        ))
+    }
 
-        let x_len = BORDER_SIZE + MAX_ITERS + x_len + MAX_ITERS + BORDER_SIZE;
-        let y_len = BORDER_SIZE + MAX_ITERS + y_len + MAX_ITERS + BORDER_SIZE;
-        let z_len = BORDER_SIZE + MAX_ITERS + 1 + MAX_ITERS + BORDER_SIZE;
+    // `origin` is the index of the origin along each axis of `cells`.
+    fn from_cells(
+        cells: ArrayD<u8>,
+        origin: Vec<isize>,
+        neighborhood: Neighborhood,
+        rule: LifeRule,
+    ) -> Self {
+        let active = Automaton::new(cells.view(), None, neighborhood, rule.clone());
+        Self {
+            active,
+            neighborhood,
+            rule,
+            num_threads: 1,
+            origin,
+        }
+    }
 
-        let mut active = Array3::zeros((z_len, x_len, y_len));
-        let scratch = Array3::zeros((z_len - 2, x_len - 2, y_len - 2));
+    fn ndim(&self) -> usize {
+        self.origin.len()
+    }
 
-        active.slice_mut(s![z_len / 2, I..-I, I..-I]).assign(z0);
+    // The number of cells in the grid
+    fn volume(&self) -> usize {
+        self.active.cells().len()
+    }
 
-        Self { active, scratch }
+    // The same cubes in the sparse representation, if it supports them.
+    fn to_sparse(&self) -> Option<SparseCubes> {
+        let coords = self
+            .active
+            .cells()
+            .indexed_iter()
+            .filter(|(_, &active)| active == 1)
+            .map(|(idx, _)| {
+                (0..self.ndim())
+                    .map(|axis| idx[axis] as isize - self.origin[axis])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        SparseCubes::from_coords(self.ndim(), &coords, self.neighborhood, self.rule.clone()).ok()
     }
 
-    fn num_active(&self) -> u16 {
-        let active = self.active.as_slice_memory_order().unwrap();
-        active.iter().map(|&cube| cube as u16).sum()
+    fn num_active(&self) -> usize {
+        self.active.count_alive()
     }
 
     fn step(&mut self) {
-        let mut neigh = self.scratch.view_mut();
-        neigh.fill(0);
+        if self.touches_edge() {
+            self.grow();
+        }
+        self.active.step();
+    }
+
+    fn set_num_threads(&mut self, num_threads: usize) {
+        self.num_threads = num_threads;
+        self.active.set_num_threads(num_threads);
+    }
+
+    // Whether any active cube lies on the outermost layer of the grid, so it
+    // could activate cubes outside it.
+    fn touches_edge(&self) -> bool {
+        let cells = self.active.cells();
+        (0..cells.ndim()).any(|axis| {
+            let last = cells.len_of(Axis(axis)) - 1;
+            [0, last].iter().any(|&idx| {
+                cells
+                    .index_axis(Axis(axis), idx)
+                    .iter()
+                    .any(|&active| active == 1)
+            })
+        })
+    }
+
+    // Rebuild the automaton with GROW_BY more cells around every edge.
+    fn grow(&mut self) {
+        let cells = self.active.cells();
+        let shape = cells
+            .shape()
+            .iter()
+            .map(|len| GROW_BY + len + GROW_BY)
+            .collect::<Vec<_>>();
 
-        for dz in -1..=1 {
-            let z_end = if dz == 1 { None } else { Some(dz - 1) };
-            let z_slice = Slice::new(dz + 1, z_end, 1);
+        let mut grown = ArrayD::zeros(IxDyn(&shape));
+        let mut interior = grown.view_mut();
+        for (axis, &len) in shape.iter().enumerate() {
+            interior.slice_axis_inplace(Axis(axis), AxisSlice::from(GROW_BY..len - GROW_BY));
+        }
+        interior.assign(&cells);
 
-            for dx in -1..=1 {
-                let x_end = if dx == 1 { None } else { Some(dx - 1) };
-                let x_slice = Slice::new(dx + 1, x_end, 1);
+        self.active = Automaton::new(grown.view(), None, self.neighborhood, self.rule.clone());
+        self.active.set_num_threads(self.num_threads);
+        for origin in &mut self.origin {
+            *origin += GROW_BY as isize;
+        }
+    }
 
-                neigh += &self.active.slice(s![z_slice, x_slice, 0..-2]);
-                neigh += &self.active.slice(s![z_slice, x_slice, 1..-1]);
-                neigh += &self.active.slice(s![z_slice, x_slice, 2..]);
+    // The (x, y) slice at these z, w, .. coordinates (innermost first), or
+    // None if it's outside the grid, i.e., entirely inactive.
+    fn slice_cells(&self, coords: &[isize]) -> Option<ArrayViewD<'_, u8>> {
+        let ndim = self.ndim();
+        let mut slice = self.active.cells();
+        for (k, &coord) in coords.iter().enumerate().rev() {
+            let idx = self.origin[ndim - 3 - k] + coord;
+            if idx < 0 || idx as usize >= slice.len_of(Axis(0)) {
+                return None;
             }
+            slice = slice.index_axis_move(Axis(0), idx as usize);
         }
+        Some(slice)
+    }
 
-        neigh -= &self.active.slice(s![1..-1, 1..-1, 1..-1]);
+    // Tile the (x, y) slices side by side with a 1px border between them:
+    // the last extra dimension (z) runs across, any others (w, ..) run down.
+    fn render(&self) -> Frame {
+        let cells = self.active.cells();
+        let shape = cells.shape();
+        let ndim = shape.len();
+        let (x_len, y_len) = (shape[ndim - 2], shape[ndim - 1]);
 
-        let mut active = self.active.slice_mut(s![1..-1, 1..-1, 1..-1]);
-        active.zip_mut_with(&neigh, |a, &n| {
-            *a = ((*a == 1 && (n == 2 || n == 3)) || (*a == 0 && n == 3)) as u8
-        });
+        let extra = &shape[..ndim - 2];
+        let num_cols = extra.last().copied().unwrap_or(1);
+        let num_rows = extra.iter().product::<usize>() / num_cols;
+
+        let mut frame = Frame::new(
+            num_cols * (y_len + 1) + 1,
+            num_rows * (x_len + 1) + 1,
+            BORDER,
+        );
+        for (idx, &active) in cells.indexed_iter() {
+            let col = if ndim > 2 { idx[ndim - 3] } else { 0 };
+            let row =
+                (0..ndim.saturating_sub(3)).fold(0, |row, axis| row * shape[axis] + idx[axis]);
+            let x = col * (y_len + 1) + 1 + idx[ndim - 1];
+            let y = row * (x_len + 1) + 1 + idx[ndim - 2];
+            frame.set(x, y, if active == 1 { ACTIVE } else { INACTIVE });
+        }
+        frame
+    }
+}
+
+// The extra dimensions, from the innermost (z) outward: z, w, v, ..
+fn extra_axis_name(k: usize) -> char {
+    b"zwvutsr".get(k).map(|&c| c as char).unwrap_or('?')
+}
+
+// A single (x, y) slice of the cubes at fixed z, w, .. coordinates.
+struct Slice<'a> {
+    cubes: &'a Cubes,
+    // Relative to the input slice, innermost dimension (z) first
+    coords: Vec<isize>,
+}
+
+impl fmt::Display for Slice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = self
+            .coords
+            .iter()
+            .enumerate()
+            .map(|(k, coord)| format!("{}={}", extra_axis_name(k), coord))
+            .collect::<Vec<_>>();
+        writeln!(f, "{}", header.join(", "))?;
+
+        let cells = self.cubes.active.cells();
+        let ndim = cells.ndim();
+        let (x_len, y_len) = (cells.len_of(Axis(ndim - 2)), cells.len_of(Axis(ndim - 1)));
+
+        match self.cubes.slice_cells(&self.coords) {
+            Some(slice) => {
+                for row in slice.outer_iter() {
+                    let line = row
+                        .iter()
+                        .map(|&active| if active == 1 { '#' } else { '.' })
+                        .collect::<String>();
+                    writeln!(f, "{}", line)?;
+                }
+            }
+            None => {
+                for _ in 0..x_len {
+                    writeln!(f, "{}", ".".repeat(y_len))?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct Cubes2 {
-    active: Array4<u8>,
-    scratch: Array4<u8>,
+impl Cubes {
+    fn slice(&self, coords: &[isize]) -> Slice<'_> {
+        Slice {
+            cubes: self,
+            coords: coords.to_vec(),
+        }
+    }
 }
 
-impl Cubes2 {
-    fn new(w0z0: &Array2<u8>) -> Self {
-        let (x_len, y_len) = w0z0.dim();
+// Every slice containing an active cube, like in the puzzle description.
+impl fmt::Display for Cubes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cells = self.active.cells();
+        let ndim = cells.ndim();
+        let extra_shape = &cells.shape()[..ndim - 2];
+        let num_slices = extra_shape.iter().product::<usize>();
 
-        let x_len = BORDER_SIZE + MAX_ITERS + x_len + MAX_ITERS + BORDER_SIZE;
-        let y_len = BORDER_SIZE + MAX_ITERS + y_len + MAX_ITERS + BORDER_SIZE;
-        let z_len = BORDER_SIZE + MAX_ITERS + 1 + MAX_ITERS + BORDER_SIZE;
-        let w_len = BORDER_SIZE + MAX_ITERS + 1 + MAX_ITERS + BORDER_SIZE;
+        let mut first = true;
+        for slice_idx in 0..num_slices {
+            // Unravel into z, w, .. coordinates, innermost first
+            let mut rest = slice_idx;
+            let mut coords = vec![0; ndim - 2];
+            for (k, coord) in coords.iter_mut().enumerate() {
+                let axis = ndim - 3 - k;
+                let len = extra_shape[axis];
+                *coord = (rest % len) as isize - self.origin[axis];
+                rest /= len;
+            }
+            let has_active = match self.slice_cells(&coords) {
+                Some(slice) => slice.iter().any(|&active| active == 1),
+                None => false,
+            };
+            if !has_active {
+                continue;
+            }
 
-        let mut active = Array4::zeros((w_len, z_len, x_len, y_len));
-        let scratch = Array4::zeros((w_len - 2, z_len - 2, x_len - 2, y_len - 2));
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            write!(f, "{}", self.slice(&coords))?;
+        }
+        Ok(())
+    }
+}
 
-        active
-            .slice_mut(s![w_len / 2, z_len / 2, I..-I, I..-I])
-            .assign(w0z0);
+const BORDER: u8 = 0;
+const INACTIVE: u8 = 1;
+const ACTIVE: u8 = 2;
+const CUBE_PALETTE: [Rgb; 3] = [[90, 90, 90], [20, 20, 30], [250, 210, 60]];
 
-        Self { active, scratch }
+// Show the cubes as one slice, or all non-empty slices if none is given.
+// Missing slice coordinates default to 0 and extra ones are ignored, so the
+// same `--slice` works for any number of dimensions.
+fn show(
+    animation: &mut Animation,
+    title: &str,
+    cubes: &Cubes,
+    slice: Option<&[isize]>,
+) -> Result<bool> {
+    match slice {
+        Some(coords) => {
+            let mut coords = coords.to_vec();
+            coords.resize(cubes.ndim() - 2, 0);
+            animation.show(title, &cubes.slice(&coords))
+        }
+        None => animation.show(title, cubes),
     }
+}
 
-    fn num_active(&self) -> u16 {
-        let active = self.active.as_slice_memory_order().unwrap();
-        active.iter().map(|&cube| cube as u16).sum()
+// Each coordinate of a sparse cube gets 16 bits of a packed u128 key,
+// biased so that it's always positive.
+const SPARSE_BITS: usize = 16;
+const SPARSE_BIAS: i128 = 1 << (SPARSE_BITS - 1);
+const MAX_SPARSE_DIMS: usize = 128 / SPARSE_BITS;
+// Keep a cell of slack on either side so neighbor offsets never carry into
+// the next coordinate.
+const MAX_SPARSE_EXTENT: usize = SPARSE_BIAS as usize - 2;
+
+// Packed coordinates don't need a DoS-resistant hash; mixing the two halves
+// with a splitmix64 finalizer is much faster than the default SipHash.
+#[derive(Default)]
+struct CoordHasher(u64);
+
+impl Hasher for CoordHasher {
+    fn finish(&self) -> u64 {
+        self.0
     }
 
-    fn step(&mut self) {
-        let mut neigh = self.scratch.view_mut();
-        neigh.fill(0);
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
 
-        for dw in -1..=1 {
-            let w_end = if dw == 1 { None } else { Some(dw - 1) };
-            let w_slice = Slice::new(dw + 1, w_end, 1);
+    fn write_u64(&mut self, n: u64) {
+        let mut x = self.0 ^ n;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        self.0 = x ^ (x >> 31);
+    }
 
-            for dz in -1..=1 {
-                let z_end = if dz == 1 { None } else { Some(dz - 1) };
-                let z_slice = Slice::new(dz + 1, z_end, 1);
+    fn write_u128(&mut self, n: u128) {
+        self.write_u64(n as u64 ^ (n >> 64) as u64);
+    }
+}
 
-                for dx in -1..=1 {
-                    let x_end = if dx == 1 { None } else { Some(dx - 1) };
-                    let x_slice = Slice::new(dx + 1, x_end, 1);
+type CoordSet = HashSet<u128, BuildHasherDefault<CoordHasher>>;
+type CoordMap<V> = HashMap<u128, V, BuildHasherDefault<CoordHasher>>;
 
-                    neigh += &self.active.slice(s![w_slice, z_slice, x_slice, 0..-2]);
-                    neigh += &self.active.slice(s![w_slice, z_slice, x_slice, 1..-1]);
-                    neigh += &self.active.slice(s![w_slice, z_slice, x_slice, 2..]);
+// Conway cubes stored as the set of active coordinates, packed into u128's.
+// Memory and time scale with the number of active cubes rather than the
+// volume of the region they span, so this wins when the cubes are sparse.
+struct SparseCubes {
+    active: CoordSet,
+    ndim: usize,
+    neighborhood: Neighborhood,
+    // Packed offsets from a cube to each of its neighbors
+    neighbor_deltas: Vec<i128>,
+    rule: LifeRule,
+}
+
+fn pack_coords(coords: &[isize]) -> u128 {
+    coords.iter().enumerate().fold(0, |key, (axis, &coord)| {
+        key | (((coord as i128 + SPARSE_BIAS) as u128) << (SPARSE_BITS * axis))
+    })
+}
+
+fn unpack_coord(key: u128, axis: usize) -> isize {
+    ((key >> (SPARSE_BITS * axis)) & ((1 << SPARSE_BITS) - 1)) as isize - SPARSE_BIAS as isize
+}
+
+impl SparseCubes {
+    fn new(
+        z0: &Array2<u8>,
+        ndim: usize,
+        neighborhood: Neighborhood,
+        rule: LifeRule,
+    ) -> Result<Self> {
+        let coords = z0
+            .indexed_iter()
+            .filter(|(_, &active)| active == 1)
+            .map(|((x, y), _)| {
+                let mut coords = vec![0; ndim - 2];
+                coords.extend_from_slice(&[x as isize, y as isize]);
+                coords
+            })
+            .collect::<Vec<_>>();
+        Self::from_coords(ndim, &coords, neighborhood, rule)
+    }
+
+    fn from_coords(
+        ndim: usize,
+        coords: &[Vec<isize>],
+        neighborhood: Neighborhood,
+        rule: LifeRule,
+    ) -> Result<Self> {
+        check_rule(ndim, &rule)?;
+        if ndim > MAX_SPARSE_DIMS {
+            return Err(anyhow!(
+                "sparse cubes support at most {} dimensions",
+                MAX_SPARSE_DIMS
+            ));
+        }
+        let directions = neighborhood
+            .directions(ndim)
+            .ok_or_else(|| anyhow!("sparse cubes don't support {:?}", neighborhood))?;
+
+        let neighbor_deltas = directions
+            .iter()
+            .map(|direction| {
+                direction
+                    .iter()
+                    .enumerate()
+                    .map(|(axis, &d)| (d as i128) << (SPARSE_BITS * axis))
+                    .sum()
+            })
+            .collect();
+
+        let extent = coords
+            .iter()
+            .flatten()
+            .map(|coord| coord.unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        if extent > MAX_SPARSE_EXTENT {
+            return Err(anyhow!("cubes too far apart for the sparse representation"));
+        }
+
+        Ok(Self {
+            active: coords.iter().map(|coords| pack_coords(coords)).collect(),
+            ndim,
+            neighborhood,
+            neighbor_deltas,
+            rule,
+        })
+    }
+
+    fn num_active(&self) -> usize {
+        self.active.len()
+    }
+
+    // The smallest (min, max) coordinates along each axis containing every
+    // active cube, or None if there aren't any.
+    fn bounds(&self) -> Option<Vec<(isize, isize)>> {
+        let mut keys = self.active.iter();
+        let first = keys.next()?;
+        let mut bounds = (0..self.ndim)
+            .map(|axis| (unpack_coord(*first, axis), unpack_coord(*first, axis)))
+            .collect::<Vec<_>>();
+        for &key in keys {
+            for (axis, (min, max)) in bounds.iter_mut().enumerate() {
+                let coord = unpack_coord(key, axis);
+                *min = (*min).min(coord);
+                *max = (*max).max(coord);
+            }
+        }
+        Some(bounds)
+    }
+
+    // How far from the origin the farthest active cube is.
+    fn extent(&self) -> usize {
+        self.bounds().map_or(0, |bounds| {
+            bounds
+                .iter()
+                .map(|(min, max)| min.unsigned_abs().max(max.unsigned_abs()))
+                .max()
+                .unwrap_or(0)
+        })
+    }
+
+    // The number of cells in the grid `to_dense` would build.
+    fn dense_volume(&self) -> usize {
+        let bounds = self.bounds().unwrap_or_else(|| vec![(0, 0); self.ndim]);
+        bounds
+            .iter()
+            .map(|(min, max)| (max - min + 3) as usize)
+            .product()
+    }
+
+    // The same cubes in a dense grid just big enough to hold them.
+    fn to_dense(&self) -> Cubes {
+        let bounds = self.bounds().unwrap_or_else(|| vec![(0, 0); self.ndim]);
+        let shape = bounds
+            .iter()
+            .map(|(min, max)| (max - min + 3) as usize)
+            .collect::<Vec<_>>();
+        let origin = bounds.iter().map(|(min, _)| 1 - min).collect::<Vec<_>>();
+
+        let mut cells = ArrayD::zeros(IxDyn(&shape));
+        for &key in &self.active {
+            let idx = (0..self.ndim)
+                .map(|axis| (unpack_coord(key, axis) + origin[axis]) as usize)
+                .collect::<Vec<_>>();
+            cells[IxDyn(&idx)] = 1;
+        }
+        Cubes::from_cells(cells, origin, self.neighborhood, self.rule.clone())
+    }
+
+    fn step(&mut self) -> Result<()> {
+        // Each step can spread the cubes by 1 in every direction.
+        if self.extent() + 1 > MAX_SPARSE_EXTENT {
+            return Err(anyhow!("sparse cubes grew past ±{}", MAX_SPARSE_EXTENT));
+        }
+
+        // Only cubes next to an active cube can have live neighbors.
+        let mut live_neighbors = CoordMap::<u16>::with_capacity_and_hasher(
+            self.active.len() * self.neighbor_deltas.len(),
+            Default::default(),
+        );
+        for &key in &self.active {
+            for &delta in &self.neighbor_deltas {
+                *live_neighbors
+                    .entry(key.wrapping_add(delta as u128))
+                    .or_insert(0) += 1;
+            }
+        }
+
+        let mut next = live_neighbors
+            .iter()
+            .filter(|&(key, &count)| {
+                self.rule
+                    .next_state(self.active.contains(key), count as usize)
+            })
+            .map(|(&key, _)| key)
+            .collect::<CoordSet>();
+
+        // Isolated cubes never showed up above.
+        if self.rule.next_state(true, 0) {
+            next.extend(
+                self.active
+                    .iter()
+                    .filter(|key| !live_neighbors.contains_key(key)),
+            );
+        }
+
+        self.active = next;
+        Ok(())
+    }
+}
+
+// Updating a sparse cube means hashing and probing the map once for each of its
+// neighbors, so we count it as costing as much as that many dense cells. Timing
+// single steps of both engines on the puzzle input puts a sparse cube at 15-30
+// dense cells in 2D and 3D, 35 in 4D, 50-85 in 5D and 140-250 in 6D, so this
+// errs on the side of the dense grid, which stays faster on anything but a
+// mostly empty grid. None if the sparse cubes don't support the neighborhood.
+fn sparse_cube_cost(neighborhood: Neighborhood, ndim: usize) -> Option<usize> {
+    neighborhood
+        .directions(ndim)
+        .map(|directions| directions.len())
+}
+
+// Conway cubes in whichever representation should be faster for their
+// current density.
+enum AutoCubes {
+    Dense(Box<Cubes>),
+    Sparse(SparseCubes),
+}
+
+impl AutoCubes {
+    fn new(
+        z0: &Array2<u8>,
+        ndim: usize,
+        neighborhood: Neighborhood,
+        rule: LifeRule,
+        num_threads: usize,
+    ) -> Result<Self> {
+        let mut cubes = Cubes::new(z0, ndim, 1, neighborhood, rule)?;
+        cubes.set_num_threads(num_threads);
+        let mut cubes = AutoCubes::Dense(Box::new(cubes));
+        cubes.rebalance(num_threads);
+        Ok(cubes)
+    }
+
+    fn num_active(&self) -> usize {
+        match self {
+            AutoCubes::Dense(cubes) => cubes.num_active(),
+            AutoCubes::Sparse(cubes) => cubes.num_active(),
+        }
+    }
+
+    fn step(&mut self, num_threads: usize) -> Result<()> {
+        match self {
+            AutoCubes::Dense(cubes) => cubes.step(),
+            AutoCubes::Sparse(cubes) => cubes.step()?,
+        }
+        self.rebalance(num_threads);
+        Ok(())
+    }
+
+    // Switch engines once the other one should be at least twice as fast, so
+    // we don't flip-flop when the two are close. Going back to dense also trims
+    // the grid down to the active region, which keeps e.g. a lone glider cheap
+    // even though the dense grid never shrinks by itself.
+    fn rebalance(&mut self, num_threads: usize) {
+        let replacement = match self {
+            AutoCubes::Dense(cubes) => {
+                let sparse_cost = sparse_cube_cost(cubes.neighborhood, cubes.ndim())
+                    .map(|cost| cubes.num_active() * cost);
+                match sparse_cost {
+                    Some(sparse_cost) if 2 * sparse_cost < cubes.volume() => {
+                        cubes.to_sparse().map(AutoCubes::Sparse)
+                    }
+                    _ => None,
                 }
             }
+            AutoCubes::Sparse(cubes)
+                if cubes.num_active() * cubes.neighbor_deltas.len() > 2 * cubes.dense_volume() =>
+            {
+                let mut dense = cubes.to_dense();
+                dense.set_num_threads(num_threads);
+                Some(AutoCubes::Dense(Box::new(dense)))
+            }
+            _ => None,
+        };
+        if let Some(replacement) = replacement {
+            *self = replacement;
         }
+    }
 
-        neigh -= &self.active.slice(s![1..-1, 1..-1, 1..-1, 1..-1]);
+    fn engine(&self) -> &'static str {
+        match self {
+            AutoCubes::Dense(_) => "dense",
+            AutoCubes::Sparse(_) => "sparse",
+        }
+    }
+}
 
-        let mut active = self.active.slice_mut(s![1..-1, 1..-1, 1..-1, 1..-1]);
-        active.zip_mut_with(&neigh, |a, &n| {
-            *a = ((*a == 1 && (n == 2 || n == 3)) || (*a == 0 && n == 3)) as u8
-        });
+// Conway cubes reduced by the symmetry of the starting state. The input
+// slice sits at the origin of every extra dimension, so the state never
+// changes when an extra coordinate is negated or two of them are swapped.
+// We only simulate the wedge of cells with 0 <= z <= w <= .., reading any
+// neighbor outside it from its mirror image inside, and weight each cell by
+// the size of its orbit when counting.
+struct SymmetricCubes {
+    // Indexed by (z, w, .., x, y). Extra coordinates run from 0 to `extent`,
+    // which is always inactive; x and y have a border of inactive cells.
+    cells: ArrayD<u8>,
+    scratch: ArrayD<u8>,
+    // Flat index of the (x, y) plane at each sorted tuple of extra
+    // coordinates, except the always inactive ones
+    planes: Vec<usize>,
+    // How many planes of the full grid each wedge plane stands for
+    orbit_sizes: Vec<usize>,
+    // The neighbors of the cell at flat index `idx` in `planes[i]` are at
+    // `idx + offsets[i][j]`, with repeats when several neighbors mirror onto
+    // the same cell.
+    offsets: Vec<Vec<isize>>,
+    directions: Vec<Vec<isize>>,
+    rule: LifeRule,
+}
+
+impl SymmetricCubes {
+    // Leaves room for `margin` steps before the grid has to grow.
+    fn new(
+        z0: &Array2<u8>,
+        ndim: usize,
+        margin: usize,
+        neighborhood: Neighborhood,
+        rule: LifeRule,
+    ) -> Result<Self> {
+        check_rule(ndim, &rule)?;
+        let directions = neighborhood
+            .directions(ndim)
+            .ok_or_else(|| anyhow!("symmetric cubes don't support {:?}", neighborhood))?;
+
+        let (x_len, y_len) = z0.dim();
+        let mut shape = vec![margin + 2; ndim - 2];
+        shape.push(margin + 1 + x_len + 1 + margin);
+        shape.push(margin + 1 + y_len + 1 + margin);
+
+        let mut cells = ArrayD::zeros(IxDyn(&shape));
+        let mut slice = cells.view_mut();
+        for _ in 0..ndim - 2 {
+            slice = slice.index_axis_move(Axis(0), 0);
+        }
+        for axis in 0..2 {
+            let len = slice.len_of(Axis(axis));
+            slice.slice_axis_inplace(Axis(axis), AxisSlice::from(margin + 1..len - margin - 1));
+        }
+        slice.assign(z0);
+
+        Ok(Self::from_cells(cells, directions, rule))
+    }
+
+    fn from_cells(cells: ArrayD<u8>, directions: Vec<Vec<isize>>, rule: LifeRule) -> Self {
+        let ndim = cells.ndim();
+        let num_extra = ndim - 2;
+        let strides = cells.strides().to_vec();
+        let extent = if num_extra > 0 {
+            cells.len_of(Axis(0)) - 1
+        } else {
+            1
+        };
+
+        // Every sorted tuple of extra coordinates below `extent`
+        let mut tuples = vec![Vec::<isize>::new()];
+        for _ in 0..num_extra {
+            tuples = tuples
+                .into_iter()
+                .flat_map(|tuple| {
+                    let start = tuple.last().copied().unwrap_or(0);
+                    (start..extent as isize).map(move |coord| {
+                        let mut tuple = tuple.clone();
+                        tuple.push(coord);
+                        tuple
+                    })
+                })
+                .collect();
+        }
+
+        let plane_index = |extra: &[isize]| -> isize {
+            extra
+                .iter()
+                .zip(&strides)
+                .map(|(coord, stride)| coord * stride)
+                .sum()
+        };
+
+        let planes = tuples
+            .iter()
+            .map(|extra| plane_index(extra) as usize)
+            .collect();
+        let orbit_sizes = tuples.iter().map(|extra| orbit_size(extra)).collect();
+        let offsets = tuples
+            .iter()
+            .map(|extra| {
+                directions
+                    .iter()
+                    .map(|direction| {
+                        // Mirror the neighbor's extra coordinates into the wedge.
+                        let (extra_delta, xy_delta) = direction.split_at(num_extra);
+                        let mut neighbor = extra
+                            .iter()
+                            .zip(extra_delta)
+                            .map(|(coord, d)| (coord + d).abs())
+                            .collect::<Vec<_>>();
+                        neighbor.sort_unstable();
+
+                        let xy_offset = xy_delta
+                            .iter()
+                            .zip(&strides[num_extra..])
+                            .map(|(d, stride)| d * stride)
+                            .sum::<isize>();
+                        plane_index(&neighbor) - plane_index(extra) + xy_offset
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let scratch = ArrayD::zeros(cells.raw_dim());
+        Self {
+            cells,
+            scratch,
+            planes,
+            orbit_sizes,
+            offsets,
+            directions,
+            rule,
+        }
+    }
+
+    // The number of cells in each (x, y) plane
+    fn plane_len(&self) -> usize {
+        let ndim = self.cells.ndim();
+        self.cells.len_of(Axis(ndim - 2)) * self.cells.len_of(Axis(ndim - 1))
+    }
+
+    fn num_active(&self) -> usize {
+        let cells = self.cells.as_slice().unwrap();
+        let plane_len = self.plane_len();
+        self.planes
+            .iter()
+            .zip(&self.orbit_sizes)
+            .map(|(&plane, &orbit_size)| {
+                let plane = &cells[plane..plane + plane_len];
+                plane.iter().filter(|&&active| active == 1).count() * orbit_size
+            })
+            .sum()
+    }
+
+    fn step(&mut self) {
+        if self.touches_edge() {
+            self.grow();
+        }
+
+        let ndim = self.cells.ndim();
+        let (x_len, y_len) = (
+            self.cells.len_of(Axis(ndim - 2)),
+            self.cells.len_of(Axis(ndim - 1)),
+        );
+        let x_stride = self.cells.strides()[ndim - 2] as usize;
+
+        let cells = self.cells.as_slice().unwrap();
+        let scratch = self.scratch.as_slice_mut().unwrap();
+        for (&plane, offsets) in self.planes.iter().zip(&self.offsets) {
+            for x in 1..x_len - 1 {
+                let row = plane + x * x_stride;
+                for idx in row + 1..row + y_len - 1 {
+                    let live_neighbors: usize = offsets
+                        .iter()
+                        .map(|&offset| cells[(idx as isize + offset) as usize] as usize)
+                        .sum();
+                    scratch[idx] = self.rule.next_state(cells[idx] == 1, live_neighbors) as u8;
+                }
+            }
+        }
+        mem::swap(&mut self.cells, &mut self.scratch);
+    }
+
+    // Whether an active cell is next to the always inactive cells at the far
+    // end of the extra axes or the x, y border. Only the wedge is ever
+    // active, and its largest extra coordinate is the last one.
+    fn touches_edge(&self) -> bool {
+        let ndim = self.cells.ndim();
+        let any_active = |axis: usize, idx: usize| {
+            self.cells
+                .index_axis(Axis(axis), idx)
+                .iter()
+                .any(|&active| active == 1)
+        };
+
+        let extra_edge = ndim > 2 && any_active(ndim - 3, self.cells.len_of(Axis(0)) - 2);
+        extra_edge
+            || (ndim - 2..ndim).any(|axis| {
+                let len = self.cells.len_of(Axis(axis));
+                any_active(axis, 1) || any_active(axis, len - 2)
+            })
+    }
+
+    // Rebuild with room for GROW_BY more cells along every axis, away from
+    // the mirror planes for the extra axes.
+    fn grow(&mut self) {
+        let ndim = self.cells.ndim();
+        let shape = self
+            .cells
+            .shape()
+            .iter()
+            .enumerate()
+            .map(|(axis, len)| {
+                if axis < ndim - 2 {
+                    len + GROW_BY
+                } else {
+                    len + 2 * GROW_BY
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut grown = ArrayD::zeros(IxDyn(&shape));
+        let mut interior = grown.view_mut();
+        for axis in 0..ndim {
+            let len = self.cells.len_of(Axis(axis));
+            let start = if axis < ndim - 2 { 0 } else { GROW_BY };
+            interior.slice_axis_inplace(Axis(axis), AxisSlice::from(start..start + len));
+        }
+        interior.assign(&self.cells);
+
+        let directions = mem::take(&mut self.directions);
+        *self = Self::from_cells(grown, directions, self.rule.clone());
+    }
+}
+
+// The number of ways to flip the signs of and reorder the extra coordinates.
+fn orbit_size(extra: &[isize]) -> usize {
+    let factorial = |n: usize| (1..=n).product::<usize>();
+
+    let num_nonzero = extra.iter().filter(|&&coord| coord != 0).count();
+    let mut orderings = factorial(extra.len());
+    for (_, group) in &extra.iter().group_by(|&&coord| coord) {
+        orderings /= factorial(group.count());
+    }
+    orderings << num_nonzero
+}
+
+// Which representation to simulate the cubes with. Frames and animations
+// always use the dense grid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Engine {
+    Dense,
+    Sparse,
+    // Switch between the two as the density changes
+    Auto,
+    // Only simulate the part of the grid that isn't a mirror image
+    Symmetric,
+}
+
+impl FromStr for Engine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dense" => Ok(Self::Dense),
+            "sparse" => Ok(Self::Sparse),
+            "auto" => Ok(Self::Auto),
+            "symmetric" => Ok(Self::Symmetric),
+            _ => Err(anyhow!("unknown engine: '{}'", s)),
+        }
+    }
+}
+
+// Time every engine on the input across dimensions, checking that they
+// agree.
+fn bench(
+    z0: &Array2<u8>,
+    max_dims: usize,
+    num_cycles: usize,
+    neighborhood: Neighborhood,
+    rule: &LifeRule,
+) -> Result<()> {
+    println!(
+        "{:>4} {:>6} {:>10} {:>12} {:>12} {:>20} {:>12}",
+        "dims", "cycles", "active", "dense", "sparse", "auto", "symmetric"
+    );
+    for ndim in 2..=max_dims {
+        let start = Instant::now();
+        let mut dense = Cubes::new(z0, ndim, 1, neighborhood, rule.clone())?;
+        for _ in 0..num_cycles {
+            dense.step();
+        }
+        let dense_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut sparse = SparseCubes::new(z0, ndim, neighborhood, rule.clone())?;
+        for _ in 0..num_cycles {
+            sparse.step()?;
+        }
+        let sparse_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut auto = AutoCubes::new(z0, ndim, neighborhood, rule.clone(), 1)?;
+        for _ in 0..num_cycles {
+            auto.step(1)?;
+        }
+        let auto_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut symmetric = SymmetricCubes::new(z0, ndim, num_cycles, neighborhood, rule.clone())?;
+        for _ in 0..num_cycles {
+            symmetric.step();
+        }
+        let symmetric_time = start.elapsed();
+
+        let num_active = dense.num_active();
+        let counts = [
+            sparse.num_active(),
+            auto.num_active(),
+            symmetric.num_active(),
+        ];
+        if counts.iter().any(|&count| count != num_active) {
+            return Err(anyhow!(
+                "engines disagree in {} dimensions: dense {}, sparse/auto/symmetric {:?}",
+                ndim,
+                num_active,
+                counts
+            ));
+        }
+
+        println!(
+            "{:>4} {:>6} {:>10} {:>12} {:>12} {:>20} {:>12}",
+            ndim,
+            num_cycles,
+            num_active,
+            format!("{:.2?}", dense_time),
+            format!("{:.2?}", sparse_time),
+            format!("{:.2?} ({})", auto_time, auto.engine()),
+            format!("{:.2?}", symmetric_time),
+        );
     }
+    Ok(())
 }
 
+// Run the boot cycles, optionally recording and/or animating every
+// generation.
+fn boot(
+    name: &str,
+    mut cubes: Cubes,
+    num_cycles: usize,
+    mut recorder: Option<FrameRecorder>,
+    mut animation: Option<&mut Animation>,
+    slice: Option<&[isize]>,
+) -> Result<usize> {
+    for cycle in 0..=num_cycles {
+        if cycle > 0 {
+            cubes.step();
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder.record(&cubes.render())?;
+        }
+        if let Some(anim) = &mut animation {
+            let title = format!("{}: cycle {}/{}", name, cycle, num_cycles);
+            if !show(anim, &title, &cubes, slice)? {
+                animation = None;
+            }
+        }
+    }
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+    Ok(cubes.num_active())
+}
+
+// usage: day17 <input> [--dims N] [--cycles N] [--engine dense|sparse|auto|symmetric] [--rule B3/S23]
+//                      [--neighborhood moore|von-neumann] [--threads N]
+//                      [--frames DIR [--gif] [--scale N]]
+//                      [--animate [--delay MS] [--slice z[,w,..]]]
+//        day17 <input> --bench [--dims MAX] [--cycles N] [--rule ..] [--neighborhood ..]
+//
+// Without --dims, runs part 1 (3 dimensions) and part 2 (4 dimensions).
 pub fn run(args: &[&str]) -> Result<()> {
     let input = fs::read_to_string(args[0]).context("Failed to read file")?;
     let z0 = parse_input(&input);
 
-    // part 1
-    time!("cubes 1:", {
-        let mut cubes = Cubes::new(&z0);
-        for _ in 0..6 {
-            cubes.step();
+    let mut dims = None;
+    let mut num_cycles = 6;
+    let mut engine = Engine::Auto;
+    let mut run_bench = false;
+    let mut rule = LifeRule::conway();
+    let mut neighborhood = Neighborhood::Moore;
+    let mut num_threads = 1;
+    let mut frames_dir = None;
+    let mut gif = false;
+    let mut scale = 4;
+    let mut animate = false;
+    let mut delay = Duration::from_millis(500);
+    let mut slice = None;
+
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        match *flag {
+            "--dims" => {
+                let n = flags.next().context("Missing number of dimensions")?;
+                dims = Some(n.parse().context("Invalid number of dimensions")?);
+            }
+            "--cycles" => {
+                let n = flags.next().context("Missing number of cycles")?;
+                num_cycles = n.parse().context("Invalid number of cycles")?;
+            }
+            "--engine" => engine = flags.next().context("Missing engine")?.parse()?,
+            "--bench" => run_bench = true,
+            "--rule" => rule = LifeRule::parse(flags.next().context("Missing rule")?)?,
+            "--neighborhood" => {
+                neighborhood = flags.next().context("Missing neighborhood")?.parse()?
+            }
+            "--threads" => {
+                let threads = flags.next().context("Missing number of threads")?;
+                num_threads = threads.parse().context("Invalid number of threads")?;
+            }
+            "--frames" => frames_dir = Some(*flags.next().context("Missing frames directory")?),
+            "--gif" => gif = true,
+            "--scale" => {
+                let n = flags.next().context("Missing scale")?;
+                scale = n.parse().context("Invalid scale")?;
+            }
+            "--animate" => animate = true,
+            "--delay" => {
+                let ms = flags.next().context("Missing delay")?;
+                delay = Duration::from_millis(ms.parse().context("Invalid delay")?);
+            }
+            "--slice" => {
+                let coords = flags.next().context("Missing slice coordinates")?;
+                let coords = coords
+                    .split(',')
+                    .map(|coord| coord.parse::<isize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("Invalid slice coordinates")?;
+                slice = Some(coords);
+            }
+            _ => return Err(anyhow!("unexpected arg: '{}'", flag)),
         }
-        dbg!(cubes.num_active());
-    });
+    }
+
+    if run_bench {
+        return bench(&z0, dims.unwrap_or(6), num_cycles, neighborhood, &rule);
+    }
+
+    let parts = match dims {
+        Some(ndim) => vec![("cubes", ndim)],
+        None => vec![("part1", 3), ("part2", 4)],
+    };
+
+    // Frames and animations look best at a fixed size, so leave room for
+    // every cycle up front instead of growing as we go.
+    let visualize = frames_dir.is_some() || animate;
+    if visualize && (engine == Engine::Sparse || engine == Engine::Symmetric) {
+        return Err(anyhow!("frames and animations need the dense engine"));
+    }
+    let margin = if visualize { num_cycles.max(1) } else { 1 };
+
+    let mut animation = if animate {
+        Some(Animation::new(delay))
+    } else {
+        None
+    };
 
-    // part 2
-    time!("cubes 2:", {
-        let mut cubes = Cubes2::new(&z0);
+    for (name, ndim) in parts {
+        time!(name, {
+            let (num_active, engine_used) = match engine {
+                Engine::Sparse => {
+                    let mut cubes = SparseCubes::new(&z0, ndim, neighborhood, rule.clone())?;
+                    for _ in 0..num_cycles {
+                        cubes.step()?;
+                    }
+                    (cubes.num_active(), "sparse")
+                }
+                Engine::Symmetric => {
+                    let mut cubes =
+                        SymmetricCubes::new(&z0, ndim, num_cycles, neighborhood, rule.clone())?;
+                    for _ in 0..num_cycles {
+                        cubes.step();
+                    }
+                    (cubes.num_active(), "symmetric")
+                }
+                Engine::Auto if !visualize => {
+                    let mut cubes =
+                        AutoCubes::new(&z0, ndim, neighborhood, rule.clone(), num_threads)?;
+                    for _ in 0..num_cycles {
+                        cubes.step(num_threads)?;
+                    }
+                    (cubes.num_active(), cubes.engine())
+                }
+                _ => {
+                    let mut cubes = Cubes::new(&z0, ndim, margin, neighborhood, rule.clone())?;
+                    cubes.set_num_threads(num_threads);
+                    let recorder = frames_dir
+                        .map(|dir| FrameRecorder::new(dir, name, &CUBE_PALETTE, scale, gif))
+                        .transpose()?;
+                    let (anim, slice) = (animation.as_mut(), slice.as_deref());
+                    let num_active = boot(name, cubes, num_cycles, recorder, anim, slice)?;
+                    (num_active, "dense")
+                }
+            };
+            println!("finished with the {} engine", engine_used);
+            dbg!(num_active);
+        });
+    }
+
+    if let Some(animation) = animation {
+        animation.finish()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_example() {
+        let z0 = parse_input(".#.\n..#\n###");
+
+        for &(ndim, expected) in &[(3, 112), (4, 848)] {
+            // Growing the grid on demand must match leaving room up front.
+            for &margin in &[0, 1, 6] {
+                let mut cubes =
+                    Cubes::new(&z0, ndim, margin, Neighborhood::Moore, LifeRule::conway()).unwrap();
+                for _ in 0..6 {
+                    cubes.step();
+                }
+                assert_eq!(cubes.num_active(), expected);
+            }
+        }
+
+        let mut cubes = Cubes::new(&z0, 3, 1, Neighborhood::Moore, LifeRule::conway()).unwrap();
+        cubes.step();
+        assert_eq!(
+            cubes.slice(&[-1]).to_string(),
+            "z=-1\n.....\n.....\n.#...\n...#.\n..#..\n"
+        );
+    }
+
+    #[test]
+    fn test_engines_agree() {
+        let z0 = parse_input(".#.\n..#\n###");
+
+        for ndim in 2..=4 {
+            for &neighborhood in &[Neighborhood::Moore, Neighborhood::VonNeumann] {
+                for rule in [LifeRule::conway(), LifeRule::parse("B2/S").unwrap()] {
+                    let mut dense = Cubes::new(&z0, ndim, 1, neighborhood, rule.clone()).unwrap();
+                    let mut sparse =
+                        SparseCubes::new(&z0, ndim, neighborhood, rule.clone()).unwrap();
+                    let mut auto =
+                        AutoCubes::new(&z0, ndim, neighborhood, rule.clone(), 1).unwrap();
+                    let mut symmetric =
+                        SymmetricCubes::new(&z0, ndim, 1, neighborhood, rule).unwrap();
+
+                    for _ in 0..6 {
+                        dense.step();
+                        sparse.step().unwrap();
+                        auto.step(1).unwrap();
+                        symmetric.step();
+                        assert_eq!(sparse.num_active(), dense.num_active());
+                        assert_eq!(auto.num_active(), dense.num_active());
+                        assert_eq!(symmetric.num_active(), dense.num_active());
+
+                        // Converting back and forth doesn't lose any cubes.
+                        let active = dense.to_sparse().unwrap().active;
+                        let round_trip = dense.to_sparse().unwrap().to_dense();
+                        assert_eq!(round_trip.to_sparse().unwrap().active, active);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_engine() {
+        // The example stays dense enough for the dense grid.
+        let z0 = parse_input(".#.\n..#\n###");
+        let mut auto = AutoCubes::new(&z0, 3, Neighborhood::Moore, LifeRule::conway(), 1).unwrap();
         for _ in 0..6 {
-            cubes.step();
+            auto.step(1).unwrap();
         }
-        dbg!(cubes.num_active());
-    });
+        assert_eq!(auto.engine(), "dense");
 
-    Ok(())
+        // A lone glider leaves the dense grid mostly empty behind it.
+        let z0 = parse_input(".#...\n..#..\n###..\n.....\n.....");
+        let mut auto = AutoCubes::new(&z0, 2, Neighborhood::Moore, LifeRule::conway(), 1).unwrap();
+        for _ in 0..100 {
+            auto.step(1).unwrap();
+        }
+        assert_eq!(auto.engine(), "sparse");
+        assert_eq!(auto.num_active(), 5);
+    }
+
+    #[test]
+    fn test_sparse_extent() {
+        // Bounded patterns can run for as long as we like.
+        let block = vec![vec![0, 0], vec![0, 1], vec![1, 0], vec![1, 1]];
+        let mut cubes =
+            SparseCubes::from_coords(2, &block, Neighborhood::Moore, LifeRule::conway()).unwrap();
+        for _ in 0..2 * MAX_SPARSE_EXTENT {
+            cubes.step().unwrap();
+        }
+        assert_eq!(cubes.num_active(), 4);
+        assert_eq!(cubes.extent(), 1);
+
+        // A glider heading off the edge of the packed coordinates errors out.
+        let edge = MAX_SPARSE_EXTENT as isize - 8;
+        let glider = [(0, 1), (1, 2), (2, 0), (2, 1), (2, 2)]
+            .iter()
+            .map(|&(x, y)| vec![edge + x, edge + y])
+            .collect::<Vec<_>>();
+        let mut cubes =
+            SparseCubes::from_coords(2, &glider, Neighborhood::Moore, LifeRule::conway()).unwrap();
+        let num_steps = (0..100).take_while(|_| cubes.step().is_ok()).count();
+        assert!(num_steps < 100);
+        assert_eq!(cubes.num_active(), 5);
+        assert_eq!(cubes.extent(), MAX_SPARSE_EXTENT);
+    }
+
+    #[test]
+    fn test_orbit_size() {
+        assert_eq!(orbit_size(&[]), 1);
+        assert_eq!(orbit_size(&[0]), 1);
+        assert_eq!(orbit_size(&[3]), 2);
+        assert_eq!(orbit_size(&[0, 0]), 1);
+        assert_eq!(orbit_size(&[0, 1]), 4);
+        assert_eq!(orbit_size(&[1, 1]), 4);
+        assert_eq!(orbit_size(&[1, 2]), 8);
+        assert_eq!(orbit_size(&[0, 1, 1]), 12);
+    }
 }
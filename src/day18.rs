@@ -1,124 +1,907 @@
-use anyhow::{Context, Result};
-use std::fs;
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use std::{collections::HashMap, convert::TryFrom, fmt, fs, str::FromStr};
 
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+impl Op {
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            '+' => Some(Op::Add),
+            '-' => Some(Op::Sub),
+            '*' => Some(Op::Mul),
+            '/' => Some(Op::Div),
+            '%' => Some(Op::Rem),
+            '^' => Some(Op::Pow),
+            _ => None,
+        }
+    }
+
+    // Integer arithmetic on i64, where overflow is an error rather than
+    // wrapping. Division truncates toward zero and the remainder takes the
+    // sign of the dividend, like Rust's / and %. Negative exponents are an
+    // error, since the result would be a fraction.
+    fn apply(self, left: i64, right: i64) -> Result<i64> {
+        let result = match self {
+            Op::Add => left.checked_add(right),
+            Op::Sub => left.checked_sub(right),
+            Op::Mul => left.checked_mul(right),
+            Op::Div | Op::Rem if right == 0 => return Err(anyhow!("division by zero")),
+            Op::Div => left.checked_div(right),
+            Op::Rem => left.checked_rem(right),
+            Op::Pow if right < 0 => return Err(anyhow!("negative exponent: {}", right)),
+            Op::Pow => match left {
+                // Only the parity of huge exponents matters for these; any
+                // other base overflows long before.
+                -1..=1 => {
+                    let exponent = if right == 0 { 0 } else { 2 - right % 2 };
+                    left.checked_pow(exponent as u32)
+                }
+                _ => u32::try_from(right)
+                    .ok()
+                    .and_then(|right| left.checked_pow(right)),
+            },
+        };
+        result.ok_or_else(|| anyhow!("overflow: {} {} {}", left, self, right))
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = match self {
+            Op::Add => '+',
+            Op::Sub => '-',
+            Op::Mul => '*',
+            Op::Div => '/',
+            Op::Rem => '%',
+            Op::Pow => '^',
+        };
+        write!(f, "{}", c)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum Token {
+    // Literals are unsigned; a leading - is a separate token.
     Num(u64),
-    Mul,
-    Add,
+    Op(Op),
     LParen,
     RParen,
 }
 
-fn tokenize(s: &str) -> Vec<Token> {
-    use Token::*;
-    s.chars()
-        .filter_map(|c| match c {
-            '0'..='9' => c.to_digit(10).map(|d| Num(d as u64)),
-            '+' => Some(Add),
-            '*' => Some(Mul),
-            '(' => Some(LParen),
-            ')' => Some(RParen),
-            _ => None,
-        })
-        .collect::<Vec<_>>()
+// A syntax error at a character offset in the line
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct ParseError {
+    offset: usize,
+    message: String,
 }
 
-fn find_matching_lparen(tokens: &[Token]) -> Option<usize> {
-    use Token::*;
-
-    let mut depth = 0;
-    for (idx, token) in tokens.iter().enumerate().rev() {
-        match token {
-            RParen => depth += 1,
-            LParen if depth == 0 => return Some(idx),
-            LParen => depth -= 1,
-            _ => (),
+impl ParseError {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        Self {
+            offset,
+            message: message.into(),
         }
     }
-    None
+
+    // The line with a caret under the error, e.g.
+    //
+    //   1 + (2 * 3
+    //       ^ unclosed parenthesis
+    fn show(&self, line: &str) -> String {
+        // Keep tabs so the caret lines up.
+        let padding = line
+            .chars()
+            .take(self.offset)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        format!("{}\n{}^ {}", line, padding, self.message)
+    }
 }
 
-fn split_lowest_precedence(tokens: &[Token], v2: bool) -> Option<(&[Token], &Token, &[Token])> {
-    use Token::*;
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
 
-    let mut lowest_idx = None;
-    let mut idx = tokens.len();
+impl std::error::Error for ParseError {}
 
-    while idx > 0 {
-        idx -= 1;
-        match tokens[idx] {
-            Add => {
-                if v2 {
-                    if lowest_idx.is_none() {
-                        lowest_idx = Some(idx);
-                    }
-                } else {
-                    lowest_idx = Some(idx);
-                    break;
+// Tokens with the character offsets they start at
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().enumerate().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '0'..='9' => {
+                let mut n = c.to_digit(10).unwrap() as u64;
+                while let Some(digit) = chars.peek().and_then(|(_, c)| c.to_digit(10)) {
+                    chars.next();
+                    n = n
+                        .checked_mul(10)
+                        .and_then(|n| n.checked_add(digit as u64))
+                        .ok_or_else(|| ParseError::new(offset, "number too large"))?;
                 }
+                Token::Num(n)
             }
-            Mul => {
-                lowest_idx = Some(idx);
-                break;
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            c => Op::from_char(c)
+                .map(Token::Op)
+                .ok_or_else(|| ParseError::new(offset, format!("unexpected character '{}'", c)))?,
+        };
+        tokens.push((offset, token));
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+}
+
+// How tightly each binary operator binds. Higher precedence binds tighter.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct PrecedenceTable {
+    ops: HashMap<Op, (u32, Assoc)>,
+}
+
+// part 1: everything evaluates left to right, except for ^ which is the
+// usual right associative power
+const V1_PRECEDENCE: &str = "+ 1 left, - 1 left, * 1 left, / 1 left, % 1 left, ^ 2 right";
+// part 2: addition and subtraction before multiplication and division
+const V2_PRECEDENCE: &str = "+ 2 left, - 2 left, * 1 left, / 1 left, % 1 left, ^ 3 right";
+
+// One operator per line or comma separated entry: <op> <precedence> <assoc>,
+// e.g. "+ 2 left, * 1 left".
+impl FromStr for PrecedenceTable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ops = HashMap::new();
+        for entry in s.split(&[',', '\n'][..]) {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (op, precedence, assoc) = entry
+                .split_whitespace()
+                .collect_tuple()
+                .ok_or_else(|| anyhow!("expected '<op> <precedence> <assoc>': {}", entry))?;
+            let op = match op.chars().collect_tuple() {
+                Some((c,)) => Op::from_char(c),
+                None => None,
             }
-            RParen => {
-                let lparen = find_matching_lparen(&tokens[..idx]).expect("no matching rparen");
-                idx = lparen;
+            .ok_or_else(|| anyhow!("unknown operator: '{}'", op))?;
+            let precedence = precedence.parse::<u32>().context("Invalid precedence")?;
+            let assoc = match assoc {
+                "left" => Assoc::Left,
+                "right" => Assoc::Right,
+                _ => return Err(anyhow!("unknown associativity: '{}'", assoc)),
+            };
+
+            if ops.insert(op, (precedence, assoc)).is_some() {
+                return Err(anyhow!("duplicate operator: '{}'", op));
+            }
+        }
+        Ok(Self { ops })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Expr {
+    Num(i64),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self) -> Result<i64> {
+        match self {
+            Expr::Num(n) => Ok(*n),
+            Expr::Neg(expr) => {
+                let n = expr.eval()?;
+                n.checked_neg().ok_or_else(|| anyhow!("overflow: -{}", n))
             }
-            Num(_) => (),
-            LParen => panic!("unexpected lparen"),
+            Expr::Binary(op, left, right) => op.apply(left.eval()?, right.eval()?),
         }
     }
+}
+
+// Fully parenthesized, so the grouping the table picked is explicit
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Neg(expr) => write!(f, "-{}", expr),
+            Expr::Binary(op, left, right) => write!(f, "({} {} {})", left, op, right),
+        }
+    }
+}
+
+// Expression trees are parsed, evaluated and printed recursively, so nesting
+// them much deeper than this (counting parentheses) can overflow the stack of a
+// debug build's test thread.
+const MAX_DEPTH: usize = 256;
+
+// Precedence climbing (Pratt) parser over a token stream
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    pos: usize,
+    // How many parentheses, negations and right operands we're inside
+    depth: usize,
+    // Offset of the end of the line, for errors about missing tokens
+    end: usize,
+    table: &'a PrecedenceTable,
+}
 
-    let lowest_idx = lowest_idx?;
+fn parse(input: &str, table: &PrecedenceTable) -> Result<Expr, ParseError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        depth: 0,
+        end: input.chars().count(),
+        table,
+    };
+    if tokens.is_empty() {
+        return Err(ParseError::new(0, "empty expression"));
+    }
 
-    let (left, right) = tokens.split_at(lowest_idx);
-    let mid = &right[0];
-    let right = &right[1..];
-    Some((left, mid, right))
+    let (expr, _) = parser.expr(0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some((offset, Token::RParen)) => Err(ParseError::new(offset, "unmatched ')'")),
+        Some((offset, _)) => Err(ParseError::new(offset, "expected an operator")),
+    }
 }
 
-fn eval(tokens: &[Token], v2: bool) -> u64 {
-    use Token::*;
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<(usize, Token)> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
 
-    if let [Num(n)] = tokens {
-        return *n;
+    // Go one level deeper for the token at `offset`, unless that's too deep.
+    fn enter(&mut self, offset: usize) -> Result<(), ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ParseError::new(offset, "nested too deeply"));
+        }
+        self.depth += 1;
+        Ok(())
     }
 
-    match split_lowest_precedence(tokens, v2) {
-        Some((left, mid, right)) => {
-            let left = eval(left, v2);
-            let right = eval(right, v2);
+    // Parse operands joined by operators that bind at least as tightly as
+    // `min_precedence`, along with the height of the tree.
+    fn expr(&mut self, min_precedence: u32) -> Result<(Expr, usize), ParseError> {
+        let (mut left, mut height) = self.atom()?;
 
-            match mid {
-                Add => left + right,
-                Mul => left * right,
-                _ => panic!("unexpected mid token"),
+        while let Some((offset, Token::Op(op))) = self.peek() {
+            let (precedence, assoc) =
+                *self.table.ops.get(&op).ok_or_else(|| {
+                    ParseError::new(offset, format!("no precedence for '{}'", op))
+                })?;
+            if precedence < min_precedence {
+                break;
+            }
+            self.pos += 1;
+
+            // Unary minus is the only operator that can start an operand.
+            match self.peek() {
+                None | Some((_, Token::RParen)) => {
+                    return Err(ParseError::new(
+                        offset,
+                        format!("missing right operand for '{}'", op),
+                    ));
+                }
+                Some((_, Token::Op(next))) if next != Op::Sub => {
+                    return Err(ParseError::new(
+                        offset,
+                        format!("missing right operand for '{}'", op),
+                    ));
+                }
+                _ => (),
+            }
+
+            // A left associative operator stops the right operand at the next
+            // operator of the same precedence; a right associative one
+            // swallows it.
+            self.enter(offset)?;
+            let (right, right_height) = match assoc {
+                Assoc::Left => self.expr(precedence + 1)?,
+                Assoc::Right => self.expr(precedence)?,
+            };
+            self.depth -= 1;
+
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+            height = height.max(right_height) + 1;
+            if self.depth + height > MAX_DEPTH {
+                return Err(ParseError::new(offset, "nested too deeply"));
             }
         }
-        None => match tokens {
-            [LParen, inner @ .., RParen] => eval(inner, v2),
-            _ => panic!("expected outer parens"),
-        },
+
+        Ok((left, height))
     }
+
+    // Unary minus binds tighter than any binary operator, so -2 ^ 2 is 4.
+    fn atom(&mut self) -> Result<(Expr, usize), ParseError> {
+        match self.next() {
+            Some((offset, Token::Num(n))) => i64::try_from(n)
+                .map(|n| (Expr::Num(n), 0))
+                .map_err(|_| ParseError::new(offset, "number too large")),
+            Some((offset, Token::Op(Op::Sub))) => match self.peek() {
+                // A negative literal, which can go one lower than a positive one
+                Some((_, Token::Num(n))) => {
+                    self.pos += 1;
+                    0_i64
+                        .checked_sub_unsigned(n)
+                        .map(|n| (Expr::Num(n), 0))
+                        .ok_or_else(|| ParseError::new(offset, "number too small"))
+                }
+                None | Some((_, Token::RParen)) => {
+                    Err(ParseError::new(offset, "missing operand for '-'"))
+                }
+                _ => {
+                    self.enter(offset)?;
+                    let (operand, height) = self.atom()?;
+                    self.depth -= 1;
+                    Ok((Expr::Neg(Box::new(operand)), height + 1))
+                }
+            },
+            Some((offset, Token::LParen)) => {
+                if let Some((_, Token::RParen)) = self.peek() {
+                    return Err(ParseError::new(offset, "empty parentheses"));
+                }
+                self.enter(offset)?;
+                let expr = self.expr(0)?;
+                self.depth -= 1;
+                match self.next() {
+                    Some((_, Token::RParen)) => Ok(expr),
+                    None => Err(ParseError::new(offset, "unclosed parenthesis")),
+                    Some((offset, _)) => {
+                        Err(ParseError::new(offset, "expected an operator or ')'"))
+                    }
+                }
+            }
+            Some((offset, Token::Op(op))) => Err(ParseError::new(
+                offset,
+                format!("missing left operand for '{}'", op),
+            )),
+            Some((offset, Token::RParen)) => Err(ParseError::new(offset, "unmatched ')'")),
+            None => Err(ParseError::new(self.end, "expected a number")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Instr {
+    Push(i64),
+    Neg,
+    Op(Op),
 }
 
-fn eval_str_v1(input: &str) -> u64 {
-    let tokens = tokenize(input);
-    eval(&tokens, false)
+// An expression in postfix order, e.g. 1 + 2 * 3 => 1 2 3 * +
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Program {
+    code: Vec<Instr>,
 }
 
-fn eval_str_v2(input: &str) -> u64 {
-    let tokens = tokenize(input);
-    eval(&tokens, true)
+// What's waiting on the shunting-yard operator stack
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    LParen(usize),
+    Neg,
+    Op(Op, u32, Assoc),
+}
+
+// The last token, which decides what the next one may be
+#[derive(Clone, Copy, Debug)]
+enum Prev {
+    Start,
+    LParen(usize),
+    Neg(usize),
+    Op(usize, Op),
+    // A number or ')'
+    Operand,
+}
+
+impl Program {
+    // Shunting-yard: operands go straight to the output, operators wait on
+    // a stack until something that binds looser shows up. Builds the same
+    // grouping and reports the same errors as the Pratt parser, but without
+    // recursion, so it's linear time and doesn't care how deep the nesting
+    // is.
+    fn compile(input: &str, table: &PrecedenceTable) -> Result<Self, ParseError> {
+        let tokens = tokenize(input)?;
+        let end = input.chars().count();
+
+        let mut code = Vec::new();
+        let mut stack = Vec::new();
+        let mut prev = Prev::Start;
+        let num_lparens = |stack: &[Pending]| {
+            stack
+                .iter()
+                .filter(|pending| matches!(pending, Pending::LParen(_)))
+                .count()
+        };
+
+        for &(offset, token) in &tokens {
+            let expect_operand = !matches!(prev, Prev::Operand);
+            prev = match (token, expect_operand) {
+                (Token::Num(n), true) => {
+                    let n = match prev {
+                        // A negative literal, which can go one lower than a
+                        // positive one
+                        Prev::Neg(neg_offset) => {
+                            stack.pop();
+                            0_i64
+                                .checked_sub_unsigned(n)
+                                .ok_or_else(|| ParseError::new(neg_offset, "number too small"))?
+                        }
+                        _ => i64::try_from(n)
+                            .map_err(|_| ParseError::new(offset, "number too large"))?,
+                    };
+                    code.push(Instr::Push(n));
+                    Prev::Operand
+                }
+                (Token::Op(Op::Sub), true) => {
+                    stack.push(Pending::Neg);
+                    Prev::Neg(offset)
+                }
+                (Token::LParen, true) => {
+                    stack.push(Pending::LParen(offset));
+                    Prev::LParen(offset)
+                }
+                (Token::Op(op), true) => {
+                    return Err(match prev {
+                        Prev::Op(prev_offset, prev_op) => {
+                            missing_right_operand(prev_offset, prev_op)
+                        }
+                        _ => ParseError::new(offset, format!("missing left operand for '{}'", op)),
+                    });
+                }
+                (Token::RParen, true) => {
+                    return Err(match prev {
+                        Prev::LParen(lparen_offset) => {
+                            ParseError::new(lparen_offset, "empty parentheses")
+                        }
+                        Prev::Op(prev_offset, prev_op) => {
+                            missing_right_operand(prev_offset, prev_op)
+                        }
+                        Prev::Neg(neg_offset) => {
+                            ParseError::new(neg_offset, "missing operand for '-'")
+                        }
+                        _ => ParseError::new(offset, "unmatched ')'"),
+                    });
+                }
+                (Token::Op(op), false) => {
+                    let (precedence, assoc) = *table.ops.get(&op).ok_or_else(|| {
+                        ParseError::new(offset, format!("no precedence for '{}'", op))
+                    })?;
+                    // Same rule as the Pratt parser: the earlier operator
+                    // wins ties if it's left associative.
+                    while let Some(&pending) = stack.last() {
+                        match pending {
+                            Pending::Neg => code.push(Instr::Neg),
+                            Pending::Op(prev_op, prev_precedence, prev_assoc)
+                                if prev_precedence > precedence
+                                    || (prev_precedence == precedence
+                                        && prev_assoc == Assoc::Left) =>
+                            {
+                                code.push(Instr::Op(prev_op))
+                            }
+                            _ => break,
+                        }
+                        stack.pop();
+                    }
+                    stack.push(Pending::Op(op, precedence, assoc));
+                    Prev::Op(offset, op)
+                }
+                (Token::RParen, false) => {
+                    loop {
+                        match stack.pop() {
+                            Some(Pending::LParen(_)) => break,
+                            Some(Pending::Neg) => code.push(Instr::Neg),
+                            Some(Pending::Op(op, _, _)) => code.push(Instr::Op(op)),
+                            None => return Err(ParseError::new(offset, "unmatched ')'")),
+                        }
+                    }
+                    Prev::Operand
+                }
+                (Token::Num(_), false) | (Token::LParen, false) => {
+                    let message = if num_lparens(&stack) > 0 {
+                        "expected an operator or ')'"
+                    } else {
+                        "expected an operator"
+                    };
+                    return Err(ParseError::new(offset, message));
+                }
+            };
+        }
+
+        match prev {
+            Prev::Start => return Err(ParseError::new(0, "empty expression")),
+            Prev::Op(offset, op) => return Err(missing_right_operand(offset, op)),
+            Prev::Neg(offset) => return Err(ParseError::new(offset, "missing operand for '-'")),
+            Prev::LParen(_) => return Err(ParseError::new(end, "expected a number")),
+            Prev::Operand => (),
+        }
+        while let Some(pending) = stack.pop() {
+            match pending {
+                Pending::LParen(offset) => {
+                    return Err(ParseError::new(offset, "unclosed parenthesis"))
+                }
+                Pending::Neg => code.push(Instr::Neg),
+                Pending::Op(op, _, _) => code.push(Instr::Op(op)),
+            }
+        }
+
+        Ok(Self { code })
+    }
+
+    fn run(&self) -> Result<i64> {
+        let mut stack = Vec::new();
+        for &instr in &self.code {
+            let value = match instr {
+                Instr::Push(n) => n,
+                Instr::Neg => {
+                    let n = stack.pop().unwrap();
+                    i64::checked_neg(n).ok_or_else(|| anyhow!("overflow: -{}", n))?
+                }
+                Instr::Op(op) => {
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    op.apply(left, right)?
+                }
+            };
+            stack.push(value);
+        }
+        Ok(stack.pop().unwrap())
+    }
+}
+
+fn missing_right_operand(offset: usize, op: Op) -> ParseError {
+    ParseError::new(offset, format!("missing right operand for '{}'", op))
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self
+            .code
+            .iter()
+            .map(|instr| match instr {
+                Instr::Push(n) => n.to_string(),
+                Instr::Neg => "neg".to_string(),
+                Instr::Op(op) => op.to_string(),
+            })
+            .join(" ");
+        write!(f, "{}", code)
+    }
+}
+
+// Evaluate with the bytecode VM, or by walking the parse tree.
+fn eval_str(input: &str, table: &PrecedenceTable, tree: bool) -> Result<i64> {
+    if tree {
+        parse(input, table)?.eval()
+    } else {
+        Program::compile(input, table)?.run()
+    }
+}
+
+// Show a syntax error under the offending line, and say which line it was.
+fn line_error(err: anyhow::Error, idx: usize, line: &str) -> anyhow::Error {
+    if let Some(err) = err.downcast_ref::<ParseError>() {
+        eprintln!("{}", err.show(line));
+    }
+    err.context(format!("line {}", idx + 1))
 }
 
 pub fn run(args: &[&str]) -> Result<()> {
     let input = fs::read_to_string(args[0]).context("Failed to read file")?;
 
-    dbg!(input.lines().map(eval_str_v1).sum::<u64>());
-    dbg!(input.lines().map(eval_str_v2).sum::<u64>());
+    let mut custom_table = None;
+    let mut print_ast = false;
+    let mut print_postfix = false;
+    let mut tree = false;
+
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        match *flag {
+            // e.g. --precedence "+ 1 left, * 2 left"
+            "--precedence" => {
+                let table = flags.next().context("Missing precedence table")?;
+                custom_table = Some(table.to_string());
+            }
+            "--precedence-file" => {
+                let path = flags.next().context("Missing precedence file")?;
+                let table = fs::read_to_string(path).context("Failed to read precedence file")?;
+                custom_table = Some(table);
+            }
+            // Print how each line groups
+            "--ast" => print_ast = true,
+            "--postfix" => print_postfix = true,
+            // Evaluate the parse trees rather than compiling to bytecode
+            "--tree" => tree = true,
+            _ => return Err(anyhow!("unexpected arg: '{}'", flag)),
+        }
+    }
+
+    let parts = match &custom_table {
+        Some(table) => vec![("custom", table.as_str())],
+        None => vec![("part1", V1_PRECEDENCE), ("part2", V2_PRECEDENCE)],
+    };
+
+    for (name, table) in parts {
+        let table = table
+            .parse::<PrecedenceTable>()
+            .context("Invalid precedence table")?;
+
+        if print_ast {
+            for (idx, line) in input.lines().enumerate() {
+                let ast = parse(line, &table).map_err(|err| line_error(err.into(), idx, line))?;
+                println!("{}: {}", name, ast);
+            }
+        }
+        if print_postfix {
+            for (idx, line) in input.lines().enumerate() {
+                let program = Program::compile(line, &table)
+                    .map_err(|err| line_error(err.into(), idx, line))?;
+                println!("{}: {}", name, program);
+            }
+        }
+
+        let sum = time!(
+            name,
+            input
+                .lines()
+                .enumerate()
+                .try_fold(0_i64, |sum, (idx, line)| {
+                    let value =
+                        eval_str(line, &table, tree).map_err(|err| line_error(err, idx, line))?;
+                    sum.checked_add(value)
+                        .ok_or_else(|| anyhow!("overflow in sum at line {}", idx + 1))
+                })
+        )?;
+        dbg!(sum);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::Rng;
+
+    #[test]
+    fn test_examples() {
+        let v1 = V1_PRECEDENCE.parse::<PrecedenceTable>().unwrap();
+        let v2 = V2_PRECEDENCE.parse::<PrecedenceTable>().unwrap();
+
+        for &(input, expected_v1, expected_v2) in &[
+            ("1 + 2 * 3 + 4 * 5 + 6", 71, 231),
+            ("1 + (2 * 3) + (4 * (5 + 6))", 51, 51),
+            ("2 * 3 + (4 * 5)", 26, 46),
+            ("5 + (8 * 3 + 9 + 3 * 4 * 3)", 437, 1445),
+            ("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))", 12240, 669_060),
+            (
+                "((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2",
+                13632,
+                23340,
+            ),
+        ] {
+            for &tree in &[false, true] {
+                assert_eq!(
+                    eval_str(input, &v1, tree).unwrap(),
+                    expected_v1,
+                    "{}",
+                    input
+                );
+                assert_eq!(
+                    eval_str(input, &v2, tree).unwrap(),
+                    expected_v2,
+                    "{}",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_precedence_table() {
+        let table = "* 2 left\n+ 1 right".parse::<PrecedenceTable>().unwrap();
+        let ast = parse("1 + 2 * 3 * 4 + 5", &table).unwrap();
+        assert_eq!(ast.to_string(), "(1 + (((2 * 3) * 4) + 5))");
+        assert_eq!(ast.eval().unwrap(), 30);
+
+        assert!("+ 1".parse::<PrecedenceTable>().is_err());
+        assert!("& 1 left".parse::<PrecedenceTable>().is_err());
+        assert!("+ 1 up".parse::<PrecedenceTable>().is_err());
+        assert!("+ 1 left, + 2 left".parse::<PrecedenceTable>().is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let v1 = V1_PRECEDENCE.parse::<PrecedenceTable>().unwrap();
+        let v2 = V2_PRECEDENCE.parse::<PrecedenceTable>().unwrap();
+
+        for &(input, expected_v1, expected_v2) in &[
+            ("12 + 3", 15, 15),
+            ("  12+3*10 ", 150, 150),
+            ("10 - 4 - 3", 3, 3),
+            ("-3 * -(2 + 1)", 9, 9),
+            ("2 * 10 - 4", 16, 12),
+            ("7 / 2", 3, 3),
+            ("-7 / 2", -3, -3),
+            ("-7 % 3", -1, -1),
+            ("2 ^ 3 ^ 2", 512, 512),
+            ("-2 ^ 2", 4, 4),
+            ("1 + 2 ^ 10", 1025, 1025),
+            ("(-1) ^ 9999999999", -1, -1),
+            ("0 ^ 0 + 0 ^ 3", 1, 1),
+            ("-9223372036854775808", i64::MIN, i64::MIN),
+        ] {
+            for &tree in &[false, true] {
+                assert_eq!(
+                    eval_str(input, &v1, tree).unwrap(),
+                    expected_v1,
+                    "{}",
+                    input
+                );
+                assert_eq!(
+                    eval_str(input, &v2, tree).unwrap(),
+                    expected_v2,
+                    "{}",
+                    input
+                );
+            }
+        }
+
+        for input in &[
+            "1 / 0",
+            "1 % (2 - 2)",
+            "2 ^ -1",
+            "2 ^ 63",
+            "9223372036854775807 + 1",
+            "-(-9223372036854775808)",
+            "-9223372036854775808 / -1",
+            "99999999999999999999",
+            "1 & 2",
+        ] {
+            assert!(eval_str(input, &v1, false).is_err(), "{}", input);
+            assert!(eval_str(input, &v1, true).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let v2 = V2_PRECEDENCE.parse::<PrecedenceTable>().unwrap();
+
+        for &(input, offset, message) in &[
+            ("", 0, "empty expression"),
+            ("1 + (2 * 3", 4, "unclosed parenthesis"),
+            ("((1)", 0, "unclosed parenthesis"),
+            ("1 + 2) * 3", 5, "unmatched ')'"),
+            (")", 0, "unmatched ')'"),
+            ("1 + () * 3", 4, "empty parentheses"),
+            ("1 +", 2, "missing right operand for '+'"),
+            ("(1 * ) + 2", 3, "missing right operand for '*'"),
+            ("1 + * 2", 2, "missing right operand for '+'"),
+            ("* 2", 0, "missing left operand for '*'"),
+            ("2 * -", 4, "missing operand for '-'"),
+            ("1 2", 2, "expected an operator"),
+            ("(1 2)", 3, "expected an operator or ')'"),
+            ("1 + 2 $ 3", 6, "unexpected character '$'"),
+            ("9223372036854775808", 0, "number too large"),
+        ] {
+            let expected = ParseError::new(offset, message);
+            assert_eq!(parse(input, &v2).unwrap_err(), expected, "{}", input);
+            assert_eq!(
+                Program::compile(input, &v2).unwrap_err(),
+                expected,
+                "{}",
+                input
+            );
+        }
+
+        let table = "+ 1 left".parse::<PrecedenceTable>().unwrap();
+        let expected = ParseError::new(6, "no precedence for '*'");
+        assert_eq!(parse("1 + 2 * 3", &table).unwrap_err(), expected);
+        assert_eq!(Program::compile("1 + 2 * 3", &table).unwrap_err(), expected);
+
+        // Only the expression tree has a depth limit.
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(parse(&nested(MAX_DEPTH), &v2).unwrap().eval().unwrap(), 1);
+        let expected = ParseError::new(MAX_DEPTH, "nested too deeply");
+        assert_eq!(parse(&nested(MAX_DEPTH + 1), &v2).unwrap_err(), expected);
+        let negated = format!("{}1", "-".repeat(MAX_DEPTH + 2));
+        assert_eq!(parse(&negated, &v2).unwrap_err(), expected);
+        let program = Program::compile(&nested(10 * MAX_DEPTH), &v2).unwrap();
+        assert_eq!(program.run().unwrap(), 1);
+
+        // Long chains of operators make deep trees too.
+        let sum = vec!["1"; MAX_DEPTH + 2].join("+");
+        let expected = ParseError::new(2 * MAX_DEPTH + 1, "nested too deeply");
+        assert_eq!(parse(&sum, &v2).unwrap_err(), expected);
+
+        assert_eq!(
+            ParseError::new(4, "unclosed parenthesis").show("1 +\t(2 * 3"),
+            "1 +\t(2 * 3\n   \t^ unclosed parenthesis"
+        );
+    }
+
+    #[test]
+    fn test_bytecode() {
+        let v2 = V2_PRECEDENCE.parse::<PrecedenceTable>().unwrap();
+        let program = Program::compile("2 * (3 + -4) ^ 2 - -(5)", &v2).unwrap();
+        assert_eq!(program.to_string(), "2 3 -4 + 2 ^ 5 neg - *");
+        assert_eq!(program.run().unwrap(), 12);
+
+        // The VM agrees with the parse tree on random expressions, including
+        // a table that mixes associativity at one precedence.
+        let tables = [
+            V1_PRECEDENCE,
+            V2_PRECEDENCE,
+            "+ 1 right, - 1 left, * 2 right, / 2 left, % 2 left, ^ 2 right",
+        ];
+        let mut rng = Rng::new(18);
+        for _ in 0..2000 {
+            let mut input = String::new();
+            let mut depth = 0;
+            for _ in 0..1 + rng.below(12) {
+                while rng.below(3) == 0 {
+                    input.push_str(if rng.below(2) == 0 { "-" } else { "(" });
+                    depth += input.ends_with('(') as usize;
+                }
+                input.push_str(&rng.below(20).to_string());
+                while depth > 0 && rng.below(3) == 0 {
+                    input.push(')');
+                    depth -= 1;
+                }
+                input.push_str(["+", "-", "*", "/", "%", "^"][rng.below(6)]);
+            }
+            input.push('7');
+            input.push_str(&")".repeat(depth));
+
+            for table in &tables {
+                let table = table.parse::<PrecedenceTable>().unwrap();
+                let tree = parse(&input, &table).unwrap();
+                let program = Program::compile(&input, &table).unwrap();
+                match (tree.eval(), program.run()) {
+                    (Ok(expected), Ok(actual)) => assert_eq!(actual, expected, "{}", input),
+                    (expected, actual) => assert!(
+                        expected.is_err() && actual.is_err(),
+                        "{}: {:?} vs {:?}",
+                        input,
+                        expected,
+                        actual
+                    ),
+                }
+            }
+        }
+
+        // Far deeper than the recursive parser could go
+        let depth = 1_000_000;
+        let input = format!("{}1{}", "(1 + ".repeat(depth), ")".repeat(depth));
+        let program = Program::compile(&input, &v2).unwrap();
+        assert_eq!(program.run().unwrap(), depth as i64 + 1);
+    }
+}
@@ -1,215 +1,1596 @@
-use anyhow::{Context, Result};
+use crate::util::Rng;
+use anyhow::{anyhow, Context, Result};
+use fixedbitset::FixedBitSet;
 use itertools::Itertools;
-use std::{fmt, fs};
+use petgraph::{algo::tarjan_scc, graph::DiGraph};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+type RuleId = usize;
+
+// Rule ids matched one after the other
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Seq(Vec<RuleId>);
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 enum Rule {
-    Or((u8, u8), (u8, u8)),
-    Or2(u8, u8),
-    Concat(u8, u8),
-    Alias(u8),
-    A,
-    B,
-    // 8: x | x 8
-    // (x .. x) ..
-    Or8(u8),
-    // 11: x y | x 11 y
-    // (x .. x y .. y) ..
-    Or11(u8, u8),
-    Empty,
+    // A literal string, e.g. "a"
+    Terminal(String),
+    // Any one of the sequences, e.g. 11: 42 31 | 42 11 31
+    Alt(Vec<Seq>),
 }
 
-impl Rule {
-    fn parse_concat(s: &str) -> Option<(u8, u8)> {
-        s.split(' ').collect_tuple().and_then(|(s1, s2)| {
-            let i1 = s1.parse::<u8>().ok()?;
-            let i2 = s2.parse::<u8>().ok()?;
-            Some((i1, i2))
-        })
-    }
+impl FromStr for Rule {
+    type Err = anyhow::Error;
 
-    fn parse(s: &str) -> Self {
-        let mut splits = s.split(" | ");
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
 
-        match (splits.next(), splits.next(), splits.next()) {
-            (Some(s), None, None) => {
-                if let Some((i1, i2)) = Self::parse_concat(s) {
-                    Self::Concat(i1, i2)
-                } else if let Ok(i) = s.parse::<u8>() {
-                    Self::Alias(i)
-                } else if s == "\"a\"" {
-                    Self::A
-                } else if s == "\"b\"" {
-                    Self::B
-                } else {
-                    panic!("bad base rule: {}", s)
-                }
+        if s.starts_with('"') {
+            let terminal = s
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .ok_or_else(|| anyhow!("unterminated string: {}", s))?;
+            // Earley matching relies on there being no empty derivations.
+            if terminal.is_empty() {
+                return Err(anyhow!("empty terminal"));
+            }
+            // Terminals can't be mixed with alternatives or sequences, e.g.,
+            // "ab" | "b" needs its own rule for each terminal.
+            if terminal.contains('"') {
+                return Err(anyhow!("more than one terminal in a rule: {}", s));
             }
-            (Some(s1), Some(s2), None) => {
-                // println!("Some(s1), Some(s2) = {}, {}", s1, s2);
+            return Ok(Self::Terminal(terminal.to_string()));
+        }
 
-                if let (Some(c1), Some(c2)) = (Self::parse_concat(s1), Self::parse_concat(s2)) {
-                    Self::Or(c1, c2)
-                } else if let (Some(i1), Some(i2)) = (s1.parse::<u8>().ok(), s2.parse::<u8>().ok())
-                {
-                    Self::Or2(i1, i2)
-                } else {
-                    panic!("bad or rule: {}", s)
+        let alts = s
+            .split('|')
+            .map(|alt| {
+                let seq = alt
+                    .split_whitespace()
+                    .map(|id| id.parse::<RuleId>().context("Invalid rule id"))
+                    .collect::<Result<Vec<_>>>()?;
+                if seq.is_empty() {
+                    return Err(anyhow!("empty alternative"));
                 }
+                Ok(Seq(seq))
+            })
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("bad rule: {}", s))?;
+
+        Ok(Self::Alt(alts))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Terminal(terminal) => write!(f, "\"{}\"", terminal),
+            Rule::Alt(alts) => {
+                let alts = alts.iter().map(|Seq(seq)| seq.iter().join(" ")).join(" | ");
+                write!(f, "{}", alts)
             }
-            _ => panic!("bad rule: {}", s),
         }
     }
 }
 
-const MAX_RULES: usize = 150;
+impl Rule {
+    // The rule as a list of alternative symbol sequences.
+    fn alternatives(&self) -> Vec<Vec<Symbol>> {
+        match self {
+            Rule::Terminal(terminal) => vec![terminal.bytes().map(Symbol::Char).collect()],
+            Rule::Alt(alts) => alts
+                .iter()
+                .map(|Seq(seq)| seq.iter().map(|&id| Symbol::Rule(id)).collect())
+                .collect(),
+        }
+    }
+}
+
+// The replacement rules from part 2, which make the grammar recursive.
+const V2_REPLACEMENTS: &str = "\
+    8: 42 | 42 8\n\
+    11: 42 31 | 42 11 31\
+";
 
+// The rules V2_REPLACEMENTS replaces or refers to.
+const V2_RULES: [RuleId; 4] = [8, 11, 42, 31];
+
+// Rules indexed by id. Ids don't need to be contiguous; the gaps are None.
 #[derive(Eq, PartialEq)]
 struct Rules {
-    rules: Vec<Rule>,
+    rules: Vec<Option<Rule>>,
 }
 
 impl Rules {
-    fn parse(s: &str, v2: bool) -> Self {
-        let mut rules = vec![Rule::Empty; MAX_RULES];
-
-        for line in s.lines() {
-            let (idx, rule) = line.split(": ").collect_tuple().unwrap();
-            let idx = idx.parse::<u8>().unwrap();
-            let rule = if v2 && idx == 8 {
-                Rule::Or8(42)
-            } else if v2 && idx == 11 {
-                Rule::Or11(42, 31)
-            } else {
-                Rule::parse(rule)
+    fn parse(s: &str) -> Result<Self> {
+        let mut rules = Self { rules: Vec::new() };
+        rules.replace(s)?;
+        Ok(rules)
+    }
+
+    fn contains(&self, id: RuleId) -> bool {
+        matches!(self.rules.get(id), Some(Some(_)))
+    }
+
+    // Add or overwrite rules, one per line.
+    fn replace(&mut self, s: &str) -> Result<()> {
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (id, rule) = line
+                .splitn(2, ':')
+                .collect_tuple()
+                .ok_or_else(|| anyhow!("expected '<id>: <rule>': {}", line))?;
+            let id = id.trim().parse::<RuleId>().context("Invalid rule id")?;
+            let rule = rule.parse::<Rule>()?;
+
+            if id >= self.rules.len() {
+                self.rules.resize(id + 1, None);
+            }
+            self.rules[id] = Some(rule);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if let Some(rule) = rule {
+                writeln!(f, "{}: {:?}", idx, rule)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Symbol {
+    Char(u8),
+    Rule(RuleId),
+}
+
+// A rule alternative with a dot before the next symbol to match, started at
+// `origin` in the input.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct Item {
+    rule: RuleId,
+    alt: usize,
+    dot: usize,
+    origin: usize,
+}
+
+// The rules flattened into plain productions, matched with an Earley
+// recognizer, so any recursion (left, right, or nested like rule 11) is
+// handled exactly.
+//
+// No rule can derive the empty string, so the textbook algorithm without the
+// nullable-completion fix is enough.
+struct Grammar {
+    alternatives: Vec<Vec<Vec<Symbol>>>,
+}
+
+impl Grammar {
+    // The rules as plain productions, without checking them. Rules that are
+    // used but never defined have no alternatives.
+    fn lower(rules: &Rules) -> Self {
+        let mut alternatives = rules
+            .rules
+            .iter()
+            .map(|rule| rule.as_ref().map(Rule::alternatives).unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        let max_used = alternatives
+            .iter()
+            .flatten()
+            .flatten()
+            .filter_map(|symbol| match *symbol {
+                Symbol::Rule(id) => Some(id),
+                Symbol::Char(_) => None,
+            })
+            .max()
+            .unwrap_or(0);
+        if max_used >= alternatives.len() {
+            alternatives.resize(max_used + 1, Vec::new());
+        }
+
+        Self { alternatives }
+    }
+
+    fn new(rules: &Rules) -> Result<Self> {
+        let grammar = Self::lower(rules);
+
+        // Every rule that we could reach must be defined.
+        let reachable = grammar.reachable(0);
+        let undefined = (0..grammar.alternatives.len())
+            .find(|&id| reachable[id] && grammar.alternatives[id].is_empty());
+        if let Some(id) = undefined {
+            return Err(anyhow!("undefined rule: {}", id));
+        }
+
+        Ok(grammar)
+    }
+
+    // Which rules `start` can expand into, including itself.
+    fn reachable(&self, start: RuleId) -> Vec<bool> {
+        let mut seen = vec![false; self.alternatives.len()];
+        let mut stack = vec![start];
+        while let Some(id) = stack.pop() {
+            if seen[id] {
+                continue;
+            }
+            seen[id] = true;
+            for symbol in self.alternatives[id].iter().flatten() {
+                if let Symbol::Rule(next) = *symbol {
+                    stack.push(next);
+                }
+            }
+        }
+        seen
+    }
+
+    fn next_symbol(&self, item: &Item) -> Option<Symbol> {
+        self.alternatives[item.rule][item.alt]
+            .get(item.dot)
+            .copied()
+    }
+
+    // The Earley chart: the items at each position in the message, including
+    // the completed ones with their origin.
+    fn chart(&self, start: RuleId, message: &[u8]) -> Vec<Vec<Item>> {
+        let mut chart: Vec<Vec<Item>> = vec![Vec::new(); message.len() + 1];
+        let mut seen: Vec<HashSet<Item>> = vec![HashSet::new(); message.len() + 1];
+        let add = |chart: &mut [Vec<Item>], seen: &mut [HashSet<Item>], pos: usize, item| {
+            if seen[pos].insert(item) {
+                chart[pos].push(item);
+            }
+        };
+
+        for alt in 0..self.alternatives[start].len() {
+            let item = Item {
+                rule: start,
+                alt,
+                dot: 0,
+                origin: 0,
             };
+            add(&mut chart, &mut seen, 0, item);
+        }
 
-            rules[idx as usize] = rule;
+        for pos in 0..=message.len() {
+            let mut idx = 0;
+            while idx < chart[pos].len() {
+                let item = chart[pos][idx];
+                idx += 1;
+
+                match self.next_symbol(&item) {
+                    // predict
+                    Some(Symbol::Rule(rule)) => {
+                        for alt in 0..self.alternatives[rule].len() {
+                            let item = Item {
+                                rule,
+                                alt,
+                                dot: 0,
+                                origin: pos,
+                            };
+                            add(&mut chart, &mut seen, pos, item);
+                        }
+                    }
+                    // scan
+                    Some(Symbol::Char(c)) => {
+                        if message.get(pos) == Some(&c) {
+                            let item = Item {
+                                dot: item.dot + 1,
+                                ..item
+                            };
+                            add(&mut chart, &mut seen, pos + 1, item);
+                        }
+                    }
+                    // complete
+                    None => {
+                        let parents = chart[item.origin]
+                            .iter()
+                            .filter(|parent| {
+                                self.next_symbol(parent) == Some(Symbol::Rule(item.rule))
+                            })
+                            .map(|parent| Item {
+                                dot: parent.dot + 1,
+                                ..*parent
+                            })
+                            .collect::<Vec<_>>();
+                        for parent in parents {
+                            add(&mut chart, &mut seen, pos, parent);
+                        }
+                    }
+                }
+            }
         }
 
-        Self { rules }
+        chart
     }
 
-    fn parse_v1(s: &str) -> Self {
-        Self::parse(s, false)
+    // Whether the whole message derives from rule `start`.
+    fn is_match(&self, start: RuleId, message: &[u8]) -> bool {
+        self.chart(start, message)[message.len()]
+            .iter()
+            .any(|item| item.rule == start && item.origin == 0 && self.next_symbol(item).is_none())
     }
-    fn parse_v2(s: &str) -> Self {
-        Self::parse(s, true)
+
+    // Up to `limit` distinct parse trees of the whole message from rule
+    // `start`. Empty if it doesn't match.
+    fn derivations(&self, start: RuleId, message: &[u8], limit: usize) -> Vec<Tree> {
+        let mut completed = HashMap::<(RuleId, usize, usize), Vec<usize>>::new();
+        for (end, items) in self.chart(start, message).iter().enumerate() {
+            for item in items {
+                if self.next_symbol(item).is_none() {
+                    completed
+                        .entry((item.rule, item.origin, end))
+                        .or_default()
+                        .push(item.alt);
+                }
+            }
+        }
+
+        let mut forest = Forest {
+            grammar: self,
+            message,
+            completed,
+            limit,
+            memo: HashMap::new(),
+            active: HashSet::new(),
+            num_cycles: 0,
+        };
+        forest.trees(start, 0, message.len())
     }
 }
 
-impl fmt::Debug for Rules {
+// Which rule and alternative matched which span of the message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Tree {
+    rule: RuleId,
+    alt: usize,
+    start: usize,
+    end: usize,
+    // Empty for terminals
+    children: Vec<Tree>,
+}
+
+impl Tree {
+    // One node per line, children indented under their parent:
+    //
+    //   0 alt 0 [0..2] "ab"
+    //     4 alt 0 [0..1] "a"
+    //     5 alt 0 [1..2] "b"
+    fn indented(&self, message: &[u8]) -> String {
+        let mut out = String::new();
+        self.write_indented(message, 0, &mut out);
+        out
+    }
+
+    fn write_indented(&self, message: &[u8], depth: usize, out: &mut String) {
+        out.push_str(&format!(
+            "{:indent$}{} alt {} [{}..{}] {:?}\n",
+            "",
+            self.rule,
+            self.alt,
+            self.start,
+            self.end,
+            String::from_utf8_lossy(&message[self.start..self.end]),
+            indent = 2 * depth,
+        ));
+        for child in &self.children {
+            child.write_indented(message, depth + 1, out);
+        }
+    }
+
+    // e.g. (0 (4 "a") (5 "b")). The alternative is implied by the children.
+    fn sexpr(&self, message: &[u8]) -> String {
+        if self.children.is_empty() {
+            return format!(
+                "({} {:?})",
+                self.rule,
+                String::from_utf8_lossy(&message[self.start..self.end])
+            );
+        }
+        let children = self
+            .children
+            .iter()
+            .map(|child| child.sexpr(message))
+            .join(" ");
+        format!("({} {})", self.rule, children)
+    }
+}
+
+// Recovers parse trees top-down from the completed spans in an Earley chart.
+struct Forest<'a> {
+    grammar: &'a Grammar,
+    message: &'a [u8],
+    // (rule, start, end) -> alternatives of `rule` that match the span
+    completed: HashMap<(RuleId, usize, usize), Vec<usize>>,
+    limit: usize,
+    memo: HashMap<(RuleId, usize, usize), Vec<Tree>>,
+    // Spans we're in the middle of deriving. Unit rules like 1: 2, 2: 1 could
+    // otherwise derive the same span forever.
+    active: HashSet<(RuleId, usize, usize)>,
+    num_cycles: usize,
+}
+
+impl<'a> Forest<'a> {
+    fn trees(&mut self, rule: RuleId, start: usize, end: usize) -> Vec<Tree> {
+        let span = (rule, start, end);
+        if let Some(trees) = self.memo.get(&span) {
+            return trees.clone();
+        }
+        let alts = match self.completed.get(&span) {
+            Some(alts) => alts.clone(),
+            None => return Vec::new(),
+        };
+        if !self.active.insert(span) {
+            self.num_cycles += 1;
+            return Vec::new();
+        }
+        let num_cycles = self.num_cycles;
+
+        let mut trees = Vec::new();
+        for alt in alts {
+            let seq = &self.grammar.alternatives[rule][alt];
+            for children in self.sequences(seq, start, end) {
+                if trees.len() == self.limit {
+                    break;
+                }
+                trees.push(Tree {
+                    rule,
+                    alt,
+                    start,
+                    end,
+                    children,
+                });
+            }
+        }
+
+        self.active.remove(&span);
+        // Cutting a cycle short loses derivations that are only cyclic from
+        // here, so don't reuse the result elsewhere.
+        if self.num_cycles == num_cycles {
+            self.memo.insert(span, trees.clone());
+        }
+        trees
+    }
+
+    // All the ways `seq` can match start..end, as the trees of its rules.
+    fn sequences(&mut self, seq: &[Symbol], start: usize, end: usize) -> Vec<Vec<Tree>> {
+        let (first, rest) = match seq.split_first() {
+            Some(split) => split,
+            None if start == end => return vec![Vec::new()],
+            None => return Vec::new(),
+        };
+
+        match *first {
+            Symbol::Char(c) => {
+                if self.message.get(start) == Some(&c) && start < end {
+                    self.sequences(rest, start + 1, end)
+                } else {
+                    Vec::new()
+                }
+            }
+            Symbol::Rule(rule) => {
+                let mut sequences = Vec::new();
+                // Every symbol matches at least one character.
+                for mid in start + 1..=end.saturating_sub(rest.len()) {
+                    if !self.completed.contains_key(&(rule, start, mid)) {
+                        continue;
+                    }
+                    let heads = self.trees(rule, start, mid);
+                    if heads.is_empty() {
+                        continue;
+                    }
+                    let tails = self.sequences(rest, mid, end);
+                    for head in &heads {
+                        for tail in &tails {
+                            if sequences.len() == self.limit {
+                                return sequences;
+                            }
+                            let mut sequence = vec![head.clone()];
+                            sequence.extend(tail.iter().cloned());
+                            sequences.push(sequence);
+                        }
+                    }
+                }
+                sequences
+            }
+        }
+    }
+}
+
+// How many distinct strings a rule derives
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Count {
+    Finite(u128),
+    // More than fits in a u128
+    TooMany,
+    // The rule can pump out ever longer strings
+    Infinite,
+}
+
+impl Count {
+    fn add(self, other: Self) -> Self {
+        match (self, other) {
+            (Count::Infinite, _) | (_, Count::Infinite) => Count::Infinite,
+            (Count::Finite(a), Count::Finite(b)) => {
+                a.checked_add(b).map_or(Count::TooMany, Count::Finite)
+            }
+            _ => Count::TooMany,
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        match (self, other) {
+            (Count::Finite(0), _) | (_, Count::Finite(0)) => Count::Finite(0),
+            (Count::Infinite, _) | (_, Count::Infinite) => Count::Infinite,
+            (Count::Finite(a), Count::Finite(b)) => {
+                a.checked_mul(b).map_or(Count::TooMany, Count::Finite)
+            }
+            _ => Count::TooMany,
+        }
+    }
+}
+
+// Stop listing a rule's strings past this many, or this long, and count them
+// with a DFA instead.
+const MAX_LISTED_STRINGS: usize = 100_000;
+const MAX_LISTED_LEN: usize = 10_000;
+
+impl fmt::Display for Count {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for (idx, rule) in self.rules.iter().enumerate() {
-            writeln!(f, "{}: {:?}", idx, rule)?;
+        match self {
+            Count::Finite(count) => write!(f, "{}", count),
+            Count::TooMany => write!(f, ">= 2^128"),
+            Count::Infinite => write!(f, "infinite"),
+        }
+    }
+}
+
+impl Grammar {
+    // The length of the shortest string each rule derives, or None if it
+    // can't derive any (undefined, or recursive without a way out).
+    fn min_lens(&self) -> Vec<Option<usize>> {
+        let mut min_lens = vec![None; self.alternatives.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for rule in 0..self.alternatives.len() {
+                let min_len = self.alternatives[rule]
+                    .iter()
+                    .filter_map(|seq| self.seq_min_len(seq, &min_lens))
+                    .min();
+                if min_len.is_some() && min_len != min_lens[rule] {
+                    min_lens[rule] = min_len;
+                    changed = true;
+                }
+            }
+        }
+        min_lens
+    }
+
+    fn seq_min_len(&self, seq: &[Symbol], min_lens: &[Option<usize>]) -> Option<usize> {
+        seq.iter()
+            .map(|symbol| match *symbol {
+                Symbol::Char(_) => Some(1),
+                Symbol::Rule(rule) => min_lens[rule],
+            })
+            .sum()
+    }
+
+    // The same grammar without the alternatives that can't derive anything
+    fn productive(&self) -> Self {
+        let min_lens = self.min_lens();
+        let alternatives = self
+            .alternatives
+            .iter()
+            .map(|alts| {
+                alts.iter()
+                    .filter(|seq| self.seq_min_len(seq, &min_lens).is_some())
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+        Self { alternatives }
+    }
+
+    // Whether each rule derives infinitely many strings. Nothing derives the
+    // empty string, so a cycle of productive rules pumps out longer and
+    // longer strings as long as one of its alternatives has another symbol
+    // besides the rule it recurses into, e.g. 8: 42 | 42 8. Cycles through
+    // unit rules like 1: 2 and 2: 1 | 3 only add more derivations.
+    fn infinite(&self) -> Vec<bool> {
+        let grammar = self.productive();
+        let edges = grammar
+            .alternatives
+            .iter()
+            .enumerate()
+            .flat_map(|(id, alts)| {
+                alts.iter().flat_map(move |seq| {
+                    seq.iter().filter_map(move |symbol| match *symbol {
+                        Symbol::Rule(next) => Some((id, next, seq.len() > 1)),
+                        Symbol::Char(_) => None,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut rule_graph = DiGraph::<(), bool, usize>::from_edges(&edges);
+        while rule_graph.node_count() < grammar.alternatives.len() {
+            rule_graph.add_node(());
+        }
+
+        let mut infinite = vec![false; grammar.alternatives.len()];
+        for scc in tarjan_scc(&rule_graph) {
+            let ids = scc.iter().map(|node| node.index()).collect::<Vec<_>>();
+            let pumps = edges
+                .iter()
+                .any(|&(id, next, grows)| grows && ids.contains(&id) && ids.contains(&next));
+            for id in ids {
+                infinite[id] = pumps;
+            }
+        }
+
+        // Anything that can expand into a pumping rule is infinite too.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &(id, next, _) in &edges {
+                if infinite[next] && !infinite[id] {
+                    infinite[id] = true;
+                    changed = true;
+                }
+            }
+        }
+        infinite
+    }
+
+    // Every distinct string each finite rule derives, sorted, or None if
+    // there are too many to list (or the rule is infinite). Recursion through
+    // unit rules means a rule can depend on itself, so we keep going until
+    // nothing changes, like `min_lens`.
+    fn listings(&self, infinite: &[bool]) -> Vec<Option<BTreeSet<Vec<u8>>>> {
+        let mut listings = infinite
+            .iter()
+            .map(|&infinite| {
+                if infinite {
+                    None
+                } else {
+                    Some(BTreeSet::new())
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for rule in 0..self.alternatives.len() {
+                if listings[rule].is_none() {
+                    continue;
+                }
+                let listing = self.list_rule(rule, &listings);
+                if listing != listings[rule] {
+                    listings[rule] = listing;
+                    changed = true;
+                }
+            }
+        }
+        listings
+    }
+
+    fn list_rule(
+        &self,
+        rule: RuleId,
+        listings: &[Option<BTreeSet<Vec<u8>>>],
+    ) -> Option<BTreeSet<Vec<u8>>> {
+        let mut strings = BTreeSet::new();
+        for seq in &self.alternatives[rule] {
+            let mut prefixes = vec![Vec::new()];
+            for symbol in seq {
+                let suffixes = match *symbol {
+                    Symbol::Char(c) => vec![vec![c]],
+                    Symbol::Rule(id) => listings[id].as_ref()?.iter().cloned().collect(),
+                };
+                let longest = |strings: &[Vec<u8>]| strings.iter().map(Vec::len).max();
+                let max_len = longest(&prefixes).unwrap_or(0) + longest(&suffixes).unwrap_or(0);
+                if prefixes.len() * suffixes.len() > MAX_LISTED_STRINGS || max_len > MAX_LISTED_LEN
+                {
+                    return None;
+                }
+                prefixes = prefixes
+                    .iter()
+                    .flat_map(|prefix| {
+                        suffixes
+                            .iter()
+                            .map(move |suffix| [&prefix[..], &suffix[..]].concat())
+                    })
+                    .collect();
+            }
+            strings.extend(prefixes);
+            if strings.len() > MAX_LISTED_STRINGS {
+                return None;
+            }
+        }
+        Some(strings)
+    }
+
+    // A lower bound on how many distinct strings of a single length each
+    // finite rule derives. That's exact for the rules we could list. Strings
+    // of fixed lengths concatenate to distinct strings, so otherwise an
+    // alternative derives at least the product of its symbols' bounds, all of
+    // one length.
+    fn same_length_counts(
+        &self,
+        infinite: &[bool],
+        listings: &[Option<BTreeSet<Vec<u8>>>],
+    ) -> Vec<Count> {
+        let mut counts = listings
+            .iter()
+            .map(|listing| {
+                let strings = listing.iter().flatten();
+                let by_len = strings.map(Vec::len).counts();
+                let most = by_len.values().max().map_or(0, |&count| count as u128);
+                Count::Finite(most)
+            })
+            .collect::<Vec<_>>();
+        let unlisted = |&rule: &usize| !infinite[rule] && listings[rule].is_none();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for rule in (0..self.alternatives.len()).filter(unlisted) {
+                let count = self.alternatives[rule]
+                    .iter()
+                    .map(|seq| {
+                        seq.iter()
+                            .fold(Count::Finite(1), |count, symbol| match *symbol {
+                                Symbol::Char(_) => count,
+                                Symbol::Rule(id) => count.mul(counts[id]),
+                            })
+                    })
+                    .max()
+                    .unwrap_or(Count::Finite(0));
+                if count != counts[rule] {
+                    counts[rule] = count;
+                    changed = true;
+                }
+            }
+        }
+        counts
+    }
+
+    // The minimal DFA matching the strings rule `start` derives
+    fn dfa(&self, start: RuleId) -> Result<Dfa> {
+        Ok(Dfa::from_nfa(&Nfa::compile(self, start)?)?.minimize())
+    }
+
+    // How many distinct strings each rule derives. Ambiguous rules derive
+    // some strings more than once, so we list and deduplicate the strings
+    // where there are few enough. Past that, the rule either has provably
+    // too many to count, or we count the paths through its DFA, which has
+    // exactly one per string but only reads a and b.
+    fn counts(&self) -> Result<Vec<Count>> {
+        let infinite = self.infinite();
+        let listings = self.listings(&infinite);
+        let same_length_counts = self.same_length_counts(&infinite, &listings);
+        (0..self.alternatives.len())
+            .map(|rule| {
+                if infinite[rule] {
+                    return Ok(Count::Infinite);
+                }
+                if let Some(strings) = &listings[rule] {
+                    return Ok(Count::Finite(strings.len() as u128));
+                }
+                if same_length_counts[rule] == Count::TooMany {
+                    return Ok(Count::TooMany);
+                }
+                let dfa = self
+                    .dfa(rule)
+                    .with_context(|| format!("rule {} has too many strings to list", rule))?;
+                Ok(dfa.count_strings())
+            })
+            .collect()
+    }
+
+    // Call `f` once with every distinct string rule `start` derives, in
+    // lexicographic order. Fails if the language is infinite.
+    fn each_string(&self, start: RuleId, f: &mut dyn FnMut(&[u8])) -> Result<()> {
+        let infinite = self.infinite();
+        if infinite[start] {
+            return Err(anyhow!("rule {} derives infinitely many strings", start));
+        }
+        match &self.listings(&infinite)[start] {
+            Some(strings) => strings.iter().for_each(|string| f(string)),
+            None => self
+                .dfa(start)
+                .with_context(|| format!("rule {} has too many strings to list", start))?
+                .each_string(f),
+        }
+        Ok(())
+    }
+
+    // A random string of at most `max_len` characters derived from rule
+    // `start`, or None if even the shortest one is too long. Each step picks
+    // uniformly among the alternatives that can still fit, so this doesn't
+    // sample the language itself uniformly.
+    fn sample(&self, start: RuleId, max_len: usize, rng: &mut Rng) -> Option<Vec<u8>> {
+        let min_lens = self.min_lens();
+        let start_len = min_lens[start]?;
+        if start_len > max_len {
+            return None;
+        }
+
+        let mut out = Vec::new();
+        // Symbols left to expand, leftmost last
+        let mut pending = vec![Symbol::Rule(start)];
+        // The shortest `pending` could still expand to
+        let mut pending_len = start_len;
+
+        while let Some(symbol) = pending.pop() {
+            match symbol {
+                Symbol::Char(c) => {
+                    out.push(c);
+                    pending_len -= 1;
+                }
+                Symbol::Rule(rule) => {
+                    let rest_len = pending_len - min_lens[rule].unwrap();
+                    let choices = self.alternatives[rule]
+                        .iter()
+                        .filter_map(|seq| {
+                            let seq_len = self.seq_min_len(seq, &min_lens)?;
+                            if out.len() + rest_len + seq_len <= max_len {
+                                Some((seq, seq_len))
+                            } else {
+                                None
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let (seq, seq_len) = choices[rng.below(choices.len())];
+                    pending.extend(seq.iter().rev());
+                    pending_len = rest_len + seq_len;
+                }
+            }
+        }
+
+        Some(out)
+    }
+}
+
+// Something suspicious about a set of rules, found before matching
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Diagnostic {
+    // Used by these rules, but never defined
+    Undefined(RuleId, Vec<RuleId>),
+    // Not used by rule 0, directly or indirectly
+    Unreachable(RuleId),
+    // Can expand to itself at the start, e.g. 1: 1 2 | 2. The Earley matcher
+    // copes, but a recursive descent parser wouldn't.
+    LeftRecursive(RuleId),
+    // Can't derive any string, e.g. 1: 2 1
+    NonProductive(RuleId),
+}
+
+impl Diagnostic {
+    // Whether matching can't go ahead
+    fn is_error(&self) -> bool {
+        matches!(self, Diagnostic::Undefined(..))
+    }
+
+    fn describe(&self, rules: &Rules) -> String {
+        let line = |id: RuleId| match rules.rules.get(id) {
+            Some(Some(rule)) => format!("{}: {}", id, rule),
+            _ => format!("{}", id),
+        };
+        match self {
+            Diagnostic::Undefined(id, used_by) => {
+                let used_by = used_by.iter().map(|&id| line(id)).join("; ");
+                format!("undefined rule {}, used by {}", id, used_by)
+            }
+            Diagnostic::Unreachable(id) => format!("unreachable: {}", line(*id)),
+            Diagnostic::LeftRecursive(id) => format!("left-recursive: {}", line(*id)),
+            Diagnostic::NonProductive(id) => format!("non-productive: {}", line(*id)),
+        }
+    }
+}
+
+fn diagnose(rules: &Rules) -> Vec<Diagnostic> {
+    let grammar = Grammar::lower(rules);
+    let num_rules = grammar.alternatives.len();
+    let is_defined = |id: RuleId| matches!(rules.rules.get(id), Some(Some(_)));
+    let mut diagnostics = Vec::new();
+
+    let mut used_by = vec![Vec::new(); num_rules];
+    for (id, alts) in grammar.alternatives.iter().enumerate() {
+        for symbol in alts.iter().flatten() {
+            if let Symbol::Rule(next) = *symbol {
+                used_by[next].push(id);
+            }
+        }
+    }
+    for (id, used_by) in used_by.iter_mut().enumerate() {
+        if !is_defined(id) && (id == 0 || !used_by.is_empty()) {
+            used_by.dedup();
+            diagnostics.push(Diagnostic::Undefined(id, used_by.clone()));
+        }
+    }
+
+    let reachable = grammar.reachable(0);
+    diagnostics.extend(
+        (0..num_rules)
+            .filter(|&id| is_defined(id) && !reachable[id])
+            .map(Diagnostic::Unreachable),
+    );
+
+    // A rule is left-recursive when it's on a cycle of rules that start
+    // each other's alternatives. No rule derives the empty string, so only
+    // the first symbol counts.
+    let left_corners = grammar
+        .alternatives
+        .iter()
+        .enumerate()
+        .flat_map(|(id, alts)| {
+            alts.iter().filter_map(move |seq| match seq.first() {
+                Some(&Symbol::Rule(first)) => Some((id, first)),
+                _ => None,
+            })
+        })
+        .collect::<Vec<_>>();
+    let left_corner_graph = DiGraph::<(), (), usize>::from_edges(&left_corners);
+    let mut left_recursive = tarjan_scc(&left_corner_graph)
+        .into_iter()
+        .filter(|scc| scc.len() > 1 || left_corner_graph.find_edge(scc[0], scc[0]).is_some())
+        .flatten()
+        .map(|node| node.index())
+        .collect::<Vec<_>>();
+    left_recursive.sort_unstable();
+    diagnostics.extend(left_recursive.into_iter().map(Diagnostic::LeftRecursive));
+
+    let min_lens = grammar.min_lens();
+    diagnostics.extend(
+        (0..num_rules)
+            .filter(|&id| is_defined(id) && min_lens[id].is_none())
+            .map(Diagnostic::NonProductive),
+    );
+
+    diagnostics
+}
+
+// Print any problems with the rules, failing if we can't match with them.
+fn check_rules(label: &str, rules: &Rules) -> Result<()> {
+    let diagnostics = diagnose(rules);
+    for diagnostic in &diagnostics {
+        println!("{}: {}", label, diagnostic.describe(rules));
+    }
+    let num_errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    if num_errors > 0 {
+        return Err(anyhow!("{}: {} error(s) in rules", label, num_errors));
+    }
+    Ok(())
+}
+
+// Give up on compiling to a DFA past this many states, and use the CFG.
+const MAX_NFA_STATES: usize = 1_000_000;
+const MAX_DFA_STATES: usize = 100_000;
+
+const EPSILON: usize = 2;
+
+// Automata only read a and b. Returns the transition index.
+fn symbol_index(c: u8) -> Option<usize> {
+    match c {
+        b'a' => Some(0),
+        b'b' => Some(1),
+        _ => None,
+    }
+}
+
+// Nondeterministic automaton with epsilon moves. Each state lists the
+// states it can move to on 'a', on 'b', and for free (EPSILON).
+struct Nfa {
+    moves: Vec<[Vec<usize>; 3]>,
+    start: usize,
+    accept: usize,
+}
+
+impl Nfa {
+    // Thompson-style construction from rule `start`, inlining every rule
+    // where it's used. Alternatives that can't derive anything are left out.
+    //
+    // Recursion is only regular in general when it's in tail position, so
+    // each recursive group of rules must be right-linear: a rule may only
+    // refer to rules in its own group as the last symbol of an alternative,
+    // e.g. 8: 42 | 42 8. Anything else, like 11: 42 31 | 42 11 31, is
+    // rejected.
+    fn compile(grammar: &Grammar, start: RuleId) -> Result<Self> {
+        let grammar = &grammar.productive();
+        let edges = grammar
+            .alternatives
+            .iter()
+            .enumerate()
+            .flat_map(|(id, alts)| {
+                alts.iter()
+                    .flatten()
+                    .filter_map(move |symbol| match *symbol {
+                        Symbol::Rule(next) => Some((id, next)),
+                        Symbol::Char(_) => None,
+                    })
+            })
+            .collect::<Vec<_>>();
+        let mut rule_graph = DiGraph::<(), (), usize>::from_edges(&edges);
+        // Rules that only match terminals might not have made it in.
+        while rule_graph.node_count() < grammar.alternatives.len() {
+            rule_graph.add_node(());
+        }
+
+        let mut scc_of = vec![0; grammar.alternatives.len()];
+        let mut sccs = Vec::new();
+        let mut recursive = Vec::new();
+        for scc in tarjan_scc(&rule_graph) {
+            recursive.push(scc.len() > 1 || rule_graph.find_edge(scc[0], scc[0]).is_some());
+            for node in &scc {
+                scc_of[node.index()] = sccs.len();
+            }
+            sccs.push(scc.into_iter().map(|node| node.index()).collect());
+        }
+
+        let mut builder = NfaBuilder {
+            grammar,
+            scc_of,
+            sccs,
+            recursive,
+            moves: Vec::new(),
+        };
+        let start_state = builder.add_state()?;
+        let accept = builder.add_state()?;
+        builder.rule(start, start_state, accept)?;
+
+        Ok(Self {
+            moves: builder.moves,
+            start: start_state,
+            accept,
+        })
+    }
+
+    fn num_states(&self) -> usize {
+        self.moves.len()
+    }
+
+    // The states reachable from `states` by epsilon moves alone, sorted
+    fn closure(&self, states: &[usize]) -> Vec<usize> {
+        let mut seen = FixedBitSet::with_capacity(self.moves.len());
+        let mut stack = states.to_vec();
+        while let Some(state) = stack.pop() {
+            if seen.put(state) {
+                continue;
+            }
+            stack.extend(&self.moves[state][EPSILON]);
+        }
+        seen.ones().collect()
+    }
+}
+
+struct NfaBuilder<'a> {
+    grammar: &'a Grammar,
+    // The strongly connected component of each rule in the graph of rule
+    // references, and whether the component is recursive
+    scc_of: Vec<usize>,
+    sccs: Vec<Vec<RuleId>>,
+    recursive: Vec<bool>,
+    moves: Vec<[Vec<usize>; 3]>,
+}
+
+impl<'a> NfaBuilder<'a> {
+    fn add_state(&mut self) -> Result<usize> {
+        if self.moves.len() == MAX_NFA_STATES {
+            return Err(anyhow!("more than {} nfa states", MAX_NFA_STATES));
+        }
+        self.moves.push(Default::default());
+        Ok(self.moves.len() - 1)
+    }
+
+    // Add the moves for `rule` to go from state `from` to `to`.
+    fn rule(&mut self, rule: RuleId, from: usize, to: usize) -> Result<()> {
+        let scc = self.scc_of[rule];
+        if !self.recursive[scc] {
+            for alt in 0..self.grammar.alternatives[rule].len() {
+                self.seq(rule, alt, from, to, None)?;
+            }
+            return Ok(());
+        }
+
+        // A fresh copy of the whole recursive group, which leaves for `to`
+        // once one of its rules stops recursing.
+        let mut entries = HashMap::new();
+        for &member in &self.sccs[scc].clone() {
+            entries.insert(member, self.add_state()?);
+        }
+        self.moves[from][EPSILON].push(entries[&rule]);
+        for (&member, &entry) in &entries {
+            for alt in 0..self.grammar.alternatives[member].len() {
+                self.seq(member, alt, entry, to, Some((scc, &entries)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn seq(
+        &mut self,
+        rule: RuleId,
+        alt: usize,
+        from: usize,
+        to: usize,
+        group: Option<(usize, &HashMap<RuleId, usize>)>,
+    ) -> Result<()> {
+        let seq = &self.grammar.alternatives[rule][alt];
+        let mut state = from;
+        for (idx, symbol) in seq.iter().enumerate() {
+            let is_last = idx + 1 == seq.len();
+            match *symbol {
+                Symbol::Char(c) => {
+                    let c = symbol_index(c)
+                        .ok_or_else(|| anyhow!("rule {} has characters besides a and b", rule))?;
+                    let next = if is_last { to } else { self.add_state()? };
+                    self.moves[state][c].push(next);
+                    state = next;
+                }
+                Symbol::Rule(next_rule) => {
+                    if let Some((scc, entries)) = group {
+                        if self.scc_of[next_rule] == scc {
+                            if !is_last {
+                                return Err(anyhow!(
+                                    "rule {} recurses into rule {} before the end",
+                                    rule,
+                                    next_rule
+                                ));
+                            }
+                            self.moves[state][EPSILON].push(entries[&next_rule]);
+                            return Ok(());
+                        }
+                    }
+                    let next = if is_last { to } else { self.add_state()? };
+                    self.rule(next_rule, state, next)?;
+                    state = next;
+                }
+            }
         }
         Ok(())
     }
 }
 
-const MAX_DEPTH: usize = 5;
+// Deterministic automaton over {a, b}. Every state has both moves, with
+// failed matches ending up in a dead state.
+#[derive(Debug)]
+struct Dfa {
+    next: Vec<[usize; 2]>,
+    accepting: Vec<bool>,
+    start: usize,
+}
+
+impl Dfa {
+    // Subset construction
+    fn from_nfa(nfa: &Nfa) -> Result<Self> {
+        let start = nfa.closure(&[nfa.start]);
+        let mut ids = HashMap::new();
+        ids.insert(start.clone(), 0);
+        let mut subsets = vec![start];
+        let mut next = Vec::new();
 
-fn build_regexes(regexes: &mut Vec<String>, rules: &[Rule], id: u8) {
-    if !regexes[id as usize].is_empty() {
-        return;
+        while next.len() < subsets.len() {
+            let subset = subsets[next.len()].clone();
+            let mut moves = [0; 2];
+            for (c, state_move) in moves.iter_mut().enumerate() {
+                let targets = subset
+                    .iter()
+                    .flat_map(|&state| nfa.moves[state][c].iter().copied())
+                    .collect::<Vec<_>>();
+                let target = nfa.closure(&targets);
+                *state_move = match ids.get(&target) {
+                    Some(&id) => id,
+                    None => {
+                        if subsets.len() == MAX_DFA_STATES {
+                            return Err(anyhow!("more than {} dfa states", MAX_DFA_STATES));
+                        }
+                        ids.insert(target.clone(), subsets.len());
+                        subsets.push(target);
+                        subsets.len() - 1
+                    }
+                };
+            }
+            next.push(moves);
+        }
+
+        let accepting = subsets
+            .iter()
+            .map(|subset| subset.binary_search(&nfa.accept).is_ok())
+            .collect();
+        Ok(Self {
+            next,
+            accepting,
+            start: 0,
+        })
     }
 
-    let rule = &rules[id as usize];
+    fn num_states(&self) -> usize {
+        self.next.len()
+    }
+
+    // Merge equivalent states by refining the accepting / rejecting split
+    // until no class has states that move to different classes (Moore's
+    // algorithm).
+    fn minimize(&self) -> Self {
+        let mut class = self
+            .accepting
+            .iter()
+            .map(|&accepting| accepting as usize)
+            .collect::<Vec<_>>();
+        let mut num_classes = 0;
+
+        loop {
+            let mut ids = HashMap::new();
+            let refined = (0..self.num_states())
+                .map(|state| {
+                    let signature = (
+                        class[state],
+                        class[self.next[state][0]],
+                        class[self.next[state][1]],
+                    );
+                    let num_ids = ids.len();
+                    *ids.entry(signature).or_insert(num_ids)
+                })
+                .collect::<Vec<_>>();
+            class = refined;
+            if ids.len() == num_classes {
+                break;
+            }
+            num_classes = ids.len();
+        }
 
-    let regex_string = match rule {
-        Rule::A => "a".to_string(),
-        Rule::B => "b".to_string(),
-        Rule::Alias(id) => {
-            build_regexes(regexes, rules, *id);
-            let r = &regexes[*id as usize];
-            r.to_string()
+        let mut next = vec![[0; 2]; num_classes];
+        let mut accepting = vec![false; num_classes];
+        for state in 0..self.num_states() {
+            next[class[state]] = [class[self.next[state][0]], class[self.next[state][1]]];
+            accepting[class[state]] = self.accepting[state];
+        }
+        Self {
+            next,
+            accepting,
+            start: class[self.start],
         }
-        Rule::Concat(id1, id2) => {
-            build_regexes(regexes, rules, *id1);
-            build_regexes(regexes, rules, *id2);
+    }
 
-            let r1 = &regexes[*id1 as usize];
-            let r2 = &regexes[*id2 as usize];
+    // The states that can still reach an accepting state
+    fn live(&self) -> Vec<bool> {
+        let mut live = self.accepting.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for state in 0..self.num_states() {
+                if !live[state] && self.next[state].iter().any(|&next| live[next]) {
+                    live[state] = true;
+                    changed = true;
+                }
+            }
+        }
+        live
+    }
 
-            format!("{}{}", r1, r2)
+    // How many strings the DFA accepts, i.e., how many paths lead from the
+    // start to an accepting state.
+    fn count_strings(&self) -> Count {
+        let live = self.live();
+        let mut counts = vec![None; self.num_states()];
+        let mut in_progress = vec![false; self.num_states()];
+        if live[self.start] {
+            self.count_from(self.start, &live, &mut counts, &mut in_progress)
+        } else {
+            Count::Finite(0)
         }
-        Rule::Or((id11, id12), (id21, id22)) => {
-            build_regexes(regexes, rules, *id11);
-            build_regexes(regexes, rules, *id12);
-            build_regexes(regexes, rules, *id21);
-            build_regexes(regexes, rules, *id22);
+    }
 
-            let r11 = &regexes[*id11 as usize];
-            let r12 = &regexes[*id12 as usize];
-            let r21 = &regexes[*id21 as usize];
-            let r22 = &regexes[*id22 as usize];
+    fn count_from(
+        &self,
+        state: usize,
+        live: &[bool],
+        counts: &mut Vec<Option<Count>>,
+        in_progress: &mut Vec<bool>,
+    ) -> Count {
+        if let Some(count) = counts[state] {
+            return count;
+        }
+        // A loop that can still end up accepting
+        if in_progress[state] {
+            return Count::Infinite;
+        }
+        in_progress[state] = true;
 
-            format!("({}{}|{}{})", r11, r12, r21, r22)
+        let mut total = Count::Finite(self.accepting[state] as u128);
+        for &next in &self.next[state] {
+            if live[next] {
+                total = total.add(self.count_from(next, live, counts, in_progress));
+            }
         }
-        Rule::Or2(id1, id2) => {
-            build_regexes(regexes, rules, *id1);
-            build_regexes(regexes, rules, *id2);
 
-            let r1 = &regexes[*id1 as usize];
-            let r2 = &regexes[*id2 as usize];
+        in_progress[state] = false;
+        counts[state] = Some(total);
+        total
+    }
+
+    // Call `f` with every string the DFA accepts, in lexicographic order.
+    // Only terminates if there are finitely many.
+    fn each_string(&self, f: &mut dyn FnMut(&[u8])) {
+        let live = self.live();
+        self.expand(self.start, &live, &mut Vec::new(), f);
+    }
 
-            format!("({}|{})", r1, r2)
+    fn expand(&self, state: usize, live: &[bool], prefix: &mut Vec<u8>, f: &mut dyn FnMut(&[u8])) {
+        if !live[state] {
+            return;
         }
-        Rule::Or8(id) => {
-            build_regexes(regexes, rules, *id);
+        if self.accepting[state] {
+            f(prefix);
+        }
+        for (&c, &next) in b"ab".iter().zip(&self.next[state]) {
+            prefix.push(c);
+            self.expand(next, live, prefix, f);
+            prefix.pop();
+        }
+    }
 
-            let r1 = &regexes[*id as usize];
+    fn is_match(&self, message: &[u8]) -> bool {
+        let mut state = self.start;
+        for &c in message {
+            state = match symbol_index(c) {
+                Some(c) => self.next[state][c],
+                None => return false,
+            };
+        }
+        self.accepting[state]
+    }
+}
 
-            format!("({})+", r1)
+// How many parse trees to show at most for an ambiguous message
+const MAX_DERIVATIONS: usize = 100;
+
+fn show_derivations(label: &str, rules: &Rules, message: &str, sexpr: bool) -> Result<()> {
+    let grammar = Grammar::new(rules)?;
+    let trees = grammar.derivations(0, message.as_bytes(), MAX_DERIVATIONS);
+
+    let more = if trees.len() == MAX_DERIVATIONS {
+        " (or more)"
+    } else {
+        ""
+    };
+    println!(
+        "{}: {} derivation(s){} of {:?}",
+        label,
+        trees.len(),
+        more,
+        message
+    );
+    for tree in &trees {
+        if sexpr {
+            println!("{}", tree.sexpr(message.as_bytes()));
+        } else {
+            print!("{}", tree.indented(message.as_bytes()));
         }
-        Rule::Or11(id1, id2) => {
-            build_regexes(regexes, rules, *id1);
-            build_regexes(regexes, rules, *id2);
+    }
+    Ok(())
+}
 
-            let r1 = &regexes[*id1 as usize];
-            let r2 = &regexes[*id2 as usize];
+// Compile the grammar to a minimal DFA, if it's regular.
+fn compile_dfa(label: &str, grammar: &Grammar) -> Result<Dfa> {
+    let nfa = Nfa::compile(grammar, 0)?;
+    let dfa = Dfa::from_nfa(&nfa)?;
+    let min_dfa = dfa.minimize();
+    println!(
+        "{}: nfa states: {}, dfa states: {}, minimized: {}",
+        label,
+        nfa.num_states(),
+        dfa.num_states(),
+        min_dfa.num_states()
+    );
+    Ok(min_dfa)
+}
 
-            // (r1){1}(r2){1} | (r1){2}(r2){2} | ...
-            let cases = (1..MAX_DEPTH)
-                .map(|i| format!("{}{{{}}}{}{{{}}}", r1, i, r2, i))
-                .join("|");
+fn count_matches(label: &str, rules: &Rules, inputs: &str, use_dfa: bool) -> Result<usize> {
+    let grammar = Grammar::new(rules)?;
 
-            format!("({})", cases)
+    let dfa = if use_dfa {
+        match time!(compile_dfa(label, &grammar)) {
+            Ok(dfa) => Some(dfa),
+            Err(err) => {
+                println!("{}: matching with the CFG, not a DFA: {}", label, err);
+                None
+            }
         }
-        Rule::Empty => panic!("empty rule: id: {}", id),
+    } else {
+        None
     };
 
-    regexes[id as usize] = regex_string;
+    let is_match = |line: &&str| match &dfa {
+        Some(dfa) => dfa.is_match(line.as_bytes()),
+        None => grammar.is_match(0, line.as_bytes()),
+    };
+    let matching_lines = inputs.lines().filter(is_match);
+    let num_matching = time!(matching_lines.count());
+
+    Ok(dbg!(num_matching))
 }
 
-fn run_regexes(rules: &[Rule], inputs: &str) {
-    let mut regexes = vec![String::new(); MAX_RULES];
+fn show_counts(label: &str, rules: &Rules) -> Result<()> {
+    let grammar = Grammar::new(rules)?;
+    let counts = grammar.counts()?;
+    for (rule, count) in counts.iter().enumerate() {
+        if rules.rules[rule].is_some() {
+            println!("{}: {}", rule, count);
+        }
+    }
+    println!("{}: {} strings", label, counts[0]);
+    Ok(())
+}
 
-    time!(build_regexes(&mut regexes, rules, 0));
+fn enumerate(label: &str, rules: &Rules) -> Result<()> {
+    let grammar = Grammar::new(rules)?;
+    if grammar.infinite()[0] {
+        println!("{}: infinite language", label);
+        return Ok(());
+    }
 
-    let base_regex = regex::RegexBuilder::new(&format!("^{}$", &regexes[0]))
-        .unicode(false)
-        .build()
-        .unwrap();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut result = Ok(());
+    grammar.each_string(0, &mut |message| {
+        if result.is_ok() {
+            result = stdout
+                .write_all(message)
+                .and_then(|_| stdout.write_all(b"\n"));
+        }
+    })?;
+    result.context("Failed to write strings")
+}
 
-    let matching_lines = inputs.lines().filter(|line| base_regex.is_match(line));
-    let num_matching = time!(matching_lines.count());
+fn generate(
+    label: &str,
+    rules: &Rules,
+    num_messages: usize,
+    max_len: usize,
+    rng: &mut Rng,
+) -> Result<()> {
+    let grammar = Grammar::new(rules)?;
+    println!("{}:", label);
+    for _ in 0..num_messages {
+        let message = grammar
+            .sample(0, max_len, rng)
+            .ok_or_else(|| anyhow!("no messages of at most {} characters", max_len))?;
+        println!("{}", String::from_utf8_lossy(&message));
+    }
+    Ok(())
+}
+
+// What to do with each part's rules
+#[derive(Clone, Copy, Debug)]
+enum Mode<'a> {
+    // Count the matching messages in the input
+    Match,
+    // Print the parse trees of a message
+    Tree(&'a str),
+    // Count the strings each rule derives
+    Count,
+    // Print every string rule 0 derives
+    Enumerate,
+    // Print this many random messages
+    Generate(usize),
+}
+
+struct RunOptions<'a> {
+    mode: Mode<'a>,
+    // Always match with the Earley recognizer, even for regular grammars
+    cfg: bool,
+    sexpr: bool,
+    max_len: usize,
+}
 
-    dbg!(num_matching);
+fn run_part(
+    label: &'static str,
+    rules: &Rules,
+    inputs: &str,
+    run_opts: &RunOptions,
+    rng: &mut Rng,
+) -> Result<()> {
+    check_rules(label, rules)?;
+
+    match run_opts.mode {
+        Mode::Match => {
+            time!(label, count_matches(label, rules, inputs, !run_opts.cfg))?;
+        }
+        Mode::Tree(message) => show_derivations(label, rules, message, run_opts.sexpr)?,
+        Mode::Count => show_counts(label, rules)?,
+        Mode::Enumerate => enumerate(label, rules)?,
+        Mode::Generate(num_messages) => {
+            generate(label, rules, num_messages, run_opts.max_len, rng)?
+        }
+    }
+    Ok(())
 }
 
 pub fn run(args: &[&str]) -> Result<()> {
     let input = fs::read_to_string(args[0]).context("Failed to read file")?;
 
+    let mut replacements = None;
+    let mut run_opts = RunOptions {
+        mode: Mode::Match,
+        cfg: false,
+        sexpr: false,
+        max_len: 100,
+    };
+    let mut seed = None;
+
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        match *flag {
+            // Rules to swap in for part 2, one per line
+            "--replace" => {
+                let path = flags.next().context("Missing replacements file")?;
+                let file = fs::read_to_string(path).context("Failed to read replacements file")?;
+                replacements = Some(file);
+            }
+            "--cfg" => run_opts.cfg = true,
+            "--tree" => run_opts.mode = Mode::Tree(flags.next().context("Missing message")?),
+            "--sexpr" => run_opts.sexpr = true,
+            "--count" => run_opts.mode = Mode::Count,
+            "--enumerate" => run_opts.mode = Mode::Enumerate,
+            "--generate" => {
+                let num_messages = flags.next().context("Missing number of messages")?;
+                let num_messages = num_messages
+                    .parse::<usize>()
+                    .context("Invalid number of messages")?;
+                run_opts.mode = Mode::Generate(num_messages);
+            }
+            "--max-len" => {
+                let max_len = flags.next().context("Missing max length")?;
+                run_opts.max_len = max_len.parse::<usize>().context("Invalid max length")?;
+            }
+            "--seed" => {
+                let value = flags.next().context("Missing seed")?;
+                seed = Some(value.parse::<u64>().context("Invalid seed")?);
+            }
+            _ => return Err(anyhow!("unexpected arg: '{}'", flag)),
+        }
+    }
+
+    let seed = match seed {
+        Some(seed) => seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+    };
+    if let Mode::Generate(_) = run_opts.mode {
+        dbg!(seed);
+    }
+    let mut rng = Rng::new(seed);
+
     let (rules_str, inputs) = input.split("\n\n").collect_tuple().unwrap();
 
     // part 1
-    let rules_v1 = Rules::parse_v1(rules_str);
-    time!(run_regexes(&rules_v1.rules, inputs));
+    let mut rules = Rules::parse(rules_str)?;
+    run_part("part1", &rules, inputs, &run_opts, &mut rng)?;
 
-    // part 2
-    let rules_v2 = Rules::parse_v2(rules_str);
-    time!(run_regexes(&rules_v2.rules, inputs));
+    // part 2: the puzzle's replacements only apply to grammars with its rules
+    let replacements = match replacements {
+        Some(replacements) => replacements,
+        None if V2_RULES.iter().all(|&id| rules.contains(id)) => V2_REPLACEMENTS.to_string(),
+        None => {
+            println!(
+                "part2: skipped, no --replace and not every rule in {:?}",
+                V2_RULES
+            );
+            return Ok(());
+        }
+    };
+    rules
+        .replace(&replacements)
+        .context("Invalid replacement rules")?;
+    run_part("part2", &rules, inputs, &run_opts, &mut rng)?;
 
     Ok(())
 }
@@ -222,10 +1603,30 @@ mod test {
     fn test_parse_rule() {
         use Rule::*;
 
-        assert_eq!(Rule::parse_v1("\"a\""), A);
-        assert_eq!(Rule::parse_v1("\"b\""), B);
-        assert_eq!(Rule::parse_v1("110 61"), Concat(110, 61));
-        assert_eq!(Rule::parse_v1("110 61 | 92 103"), Or((110, 61), (92, 103)));
+        assert_eq!("\"a\"".parse::<Rule>().unwrap(), Terminal("a".to_string()));
+        assert_eq!(
+            "\"ab\"".parse::<Rule>().unwrap(),
+            Terminal("ab".to_string())
+        );
+        assert_eq!(
+            "110 61".parse::<Rule>().unwrap(),
+            Alt(vec![Seq(vec![110, 61])])
+        );
+        assert_eq!(
+            "110 61 | 92 103".parse::<Rule>().unwrap(),
+            Alt(vec![Seq(vec![110, 61]), Seq(vec![92, 103])])
+        );
+        assert_eq!(
+            "42 | 1000 11 31 | 7".parse::<Rule>().unwrap(),
+            Alt(vec![Seq(vec![42]), Seq(vec![1000, 11, 31]), Seq(vec![7])])
+        );
+
+        assert!("\"a".parse::<Rule>().is_err());
+        assert!("\"\"".parse::<Rule>().is_err());
+        assert!("\"ab\" | \"b\"".parse::<Rule>().is_err());
+        assert!("\"a\" \"b\"".parse::<Rule>().is_err());
+        assert!("1 | | 2".parse::<Rule>().is_err());
+        assert!("1 x".parse::<Rule>().is_err());
     }
 
     #[test]
@@ -239,18 +1640,310 @@ mod test {
             2: 3\
         ";
 
+        let alt = |seqs: &[&[RuleId]]| {
+            Some(Rule::Alt(
+                seqs.iter().map(|seq| Seq(seq.to_vec())).collect(),
+            ))
+        };
         let expected = Rules {
             rules: vec![
-                Rule::Concat(4, 1),
-                Rule::Or((2, 3), (3, 2)),
-                Rule::Alias(3),
-                Rule::Or((4, 5), (5, 4)),
-                Rule::A,
-                Rule::B,
+                alt(&[&[4, 1]]),
+                alt(&[&[2, 3], &[3, 2]]),
+                alt(&[&[3]]),
+                alt(&[&[4, 5], &[5, 4]]),
+                Some(Rule::Terminal("a".to_string())),
+                Some(Rule::Terminal("b".to_string())),
             ],
         };
-        let actual = Rules::parse_v1(input);
+        let actual = Rules::parse(input).unwrap();
 
         assert_eq!(actual, expected);
+        assert!(actual.contains(5));
+        assert!(!actual.contains(6));
+    }
+
+    #[test]
+    fn test_recursive() {
+        // a^n b^n, which no regex can match
+        let rules = Rules::parse("0: 1 0 2 | 1 2\n1: \"a\"\n2: \"b\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        for message in &["ab", "aabb", "aaaaabbbbb"] {
+            assert!(grammar.is_match(0, message.as_bytes()), "{}", message);
+        }
+        for message in &["", "a", "ba", "aab", "abab", "aaaaabbbb"] {
+            assert!(!grammar.is_match(0, message.as_bytes()), "{}", message);
+        }
+
+        // left recursion
+        let mut rules = Rules::parse("0: 1 2\n1: 3\n2: \"b\"\n3: \"a\"").unwrap();
+        rules.replace("1: 1 3 | 3").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert!(grammar.is_match(0, b"ab"));
+        assert!(grammar.is_match(0, b"aaaab"));
+        assert!(!grammar.is_match(0, b"aaaa"));
+        assert!(!grammar.is_match(0, b"b"));
+
+        let rules = Rules::parse("0: 1 2\n1: \"a\"").unwrap();
+        assert!(Grammar::new(&rules).is_err());
+
+        // sparse ids and multi-character terminals
+        let rules = Rules::parse("0: 70000 2 | 2\n70000: \"ab\"\n2: \"ba\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert!(grammar.is_match(0, b"abba"));
+        assert!(grammar.is_match(0, b"ba"));
+        assert!(!grammar.is_match(0, b"ab"));
+    }
+
+    #[test]
+    fn test_derivations() {
+        let rules = Rules::parse("0: 4 1\n1: 5 | 4 5\n4: \"a\"\n5: \"b\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        let trees = grammar.derivations(0, b"aab", 10);
+        assert_eq!(trees.len(), 1);
+        assert_eq!(
+            trees[0].sexpr(b"aab"),
+            "(0 (4 \"a\") (1 (4 \"a\") (5 \"b\")))"
+        );
+        assert_eq!(
+            trees[0].indented(b"aab"),
+            "\
+            0 alt 0 [0..3] \"aab\"\n\
+            \x20 4 alt 0 [0..1] \"a\"\n\
+            \x20 1 alt 1 [1..3] \"ab\"\n\
+            \x20   4 alt 0 [1..2] \"a\"\n\
+            \x20   5 alt 0 [2..3] \"b\"\n\
+            "
+        );
+        assert!(grammar.derivations(0, b"aa", 10).is_empty());
+
+        // ambiguous: both ways to bracket three a's
+        let rules = Rules::parse("0: 0 0 | 1\n1: \"a\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        let trees = grammar
+            .derivations(0, b"aaa", 10)
+            .iter()
+            .map(|tree| tree.sexpr(b"aaa"))
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            trees,
+            vec![
+                "(0 (0 (0 (1 \"a\")) (0 (1 \"a\"))) (0 (1 \"a\")))",
+                "(0 (0 (1 \"a\")) (0 (0 (1 \"a\")) (0 (1 \"a\"))))",
+            ]
+        );
+        // Catalan many trees, cut off at the limit
+        assert_eq!(grammar.derivations(0, b"aaaaaaaaaa", 100).len(), 100);
+
+        // unit cycle: 0 -> 1 -> 0 -> ... only counts the acyclic derivations
+        let rules = Rules::parse("0: 1 | 2\n1: 0 | 2\n2: \"a\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        let trees = grammar
+            .derivations(0, b"a", 10)
+            .iter()
+            .map(|tree| tree.sexpr(b"a"))
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(trees, vec!["(0 (1 (2 \"a\")))", "(0 (2 \"a\"))"]);
+    }
+
+    #[test]
+    fn test_language() {
+        let strings = |grammar: &Grammar| {
+            let mut strings = Vec::new();
+            grammar
+                .each_string(0, &mut |message| {
+                    strings.push(String::from_utf8(message.to_vec()).unwrap())
+                })
+                .unwrap();
+            strings
+        };
+
+        let rules = Rules::parse("0: 4 1\n1: 4 | 5 5\n4: \"a\"\n5: \"ba\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert_eq!(grammar.min_lens()[..2], [Some(2), Some(1)]);
+        assert_eq!(
+            grammar.counts().unwrap()[..2],
+            [Count::Finite(2), Count::Finite(2)]
+        );
+        assert_eq!(strings(&grammar), vec!["aa", "ababa"]);
+
+        // Four derivations, but only two distinct strings
+        let rules = Rules::parse("0: 1 1 | 2\n1: \"a\"\n2: 4 | 1 3\n3: 1 | 5\n4: \"aa\"\n5: \"b\"")
+            .unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert_eq!(grammar.counts().unwrap()[0], Count::Finite(2));
+        assert_eq!(strings(&grammar), vec!["aa", "ab"]);
+
+        // Recursion through unit rules doesn't make the language infinite.
+        let rules = Rules::parse("0: 1 | 2\n1: 0 | 3\n2: \"a\"\n3: \"b\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert_eq!(grammar.infinite()[..2], [false, false]);
+        assert_eq!(grammar.counts().unwrap()[0], Count::Finite(2));
+        assert_eq!(strings(&grammar), vec!["a", "b"]);
+
+        // 32 strings each for 42 (all of length 5) and 31 (of length 5 or 6),
+        // and 0: 42 42 31
+        let mut rules = Rules::parse(
+            "\
+            0: 8 11\n8: 42\n11: 42 31\n\
+            42: 2 2 2 2 2\n31: 3 2 2 2 2\n2: 4 | 5\n3: 4 4 | 5\n\
+            4: \"a\"\n5: \"b\"\
+            ",
+        )
+        .unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        let counts = grammar.counts().unwrap();
+        assert_eq!(counts[42], Count::Finite(32));
+        assert_eq!(counts[31], Count::Finite(32));
+        assert_eq!(counts[0], Count::Finite(32 * 32 * 32));
+
+        rules.replace(V2_REPLACEMENTS).unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert_eq!(grammar.counts().unwrap()[0], Count::Infinite);
+        assert!(grammar.each_string(0, &mut |_| ()).is_err());
+
+        // Samples stay in the language and under the length bound.
+        let mut rng = Rng::new(42);
+        for max_len in 15..40 {
+            let message = grammar.sample(0, max_len, &mut rng).unwrap();
+            assert!(message.len() <= max_len);
+            assert!(grammar.is_match(0, &message));
+        }
+        assert_eq!(grammar.sample(0, 14, &mut rng), None);
+
+        // Terminals besides a and b are fine while there are few enough
+        // strings to list.
+        let rules = Rules::parse("0: 1 2 | 2 1\n1: \"x\"\n2: 1 | 3\n3: \"yz\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert_eq!(grammar.counts().unwrap()[0], Count::Finite(3));
+        assert_eq!(strings(&grammar), vec!["xx", "xyz", "yzx"]);
+
+        // Rule i derives every string of length 1 to 2^(25 - i), which is too
+        // many to list from rule 21 on, and too many to count from rule 18 on.
+        let nested = (0..25)
+            .map(|i| format!("{}: {} {} | {}\n", i, i + 1, i + 1, i + 1))
+            .collect::<String>();
+        let rules = Rules::parse(&format!("{}25: 26 | 27\n26: \"a\"\n27: \"b\"", nested)).unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        let counts = grammar.counts().unwrap();
+        let all_up_to = |len: u32| Count::Finite((1 << (len + 1)) - 2);
+        assert_eq!(counts[25], all_up_to(1));
+        assert_eq!(counts[22], all_up_to(8));
+        assert_eq!(counts[21], all_up_to(16));
+        assert_eq!(counts[19], all_up_to(64));
+        assert_eq!(counts[18], Count::TooMany);
+        assert_eq!(counts[0], Count::TooMany);
+
+        // Without a DFA, there's no way to count rule 21's strings.
+        let rules = Rules::parse(&format!("{}25: 26 | 27\n26: \"a\"\n27: \"c\"", nested)).unwrap();
+        assert!(Grammar::new(&rules).unwrap().counts().is_err());
+
+        // A recursive rule without a way out derives nothing.
+        let rules = Rules::parse("0: 1 | 2\n1: 1 2\n2: \"a\"").unwrap();
+        let grammar = Grammar::new(&rules).unwrap();
+        assert_eq!(grammar.min_lens()[1], None);
+        assert_eq!(
+            grammar.counts().unwrap()[..2],
+            [Count::Finite(1), Count::Finite(0)]
+        );
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let rules = Rules::parse(
+            "\
+            0: 1 2 | 9\n\
+            1: 1 3 | 3\n\
+            2: 6 4\n\
+            3: \"a\"\n\
+            4: \"b\"\n\
+            5: 3\n\
+            6: 2 3\
+            ",
+        )
+        .unwrap();
+
+        use Diagnostic::*;
+        let diagnostics = diagnose(&rules);
+        assert_eq!(
+            diagnostics,
+            vec![
+                Undefined(9, vec![0]),
+                Unreachable(5),
+                LeftRecursive(1),
+                LeftRecursive(2),
+                LeftRecursive(6),
+                NonProductive(0),
+                NonProductive(2),
+                NonProductive(6),
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].describe(&rules),
+            "undefined rule 9, used by 0: 1 2 | 9"
+        );
+        assert_eq!(
+            diagnostics[2].describe(&rules),
+            "left-recursive: 1: 1 3 | 3"
+        );
+        assert!(check_rules("test", &rules).is_err());
+
+        let mut rules = Rules::parse("0: 8 11\n8: 42\n11: 42 31\n42: \"a\"\n31: \"b\"").unwrap();
+        assert_eq!(diagnose(&rules), vec![]);
+        rules.replace(V2_REPLACEMENTS).unwrap();
+        assert_eq!(diagnose(&rules), vec![]);
+    }
+
+    #[test]
+    fn test_dfa() {
+        // Every string over {a, b} up to some length
+        let messages = (0..=10)
+            .flat_map(|len| {
+                (0..1 << len).map(move |bits: u32| {
+                    (0..len)
+                        .map(|i| if bits >> i & 1 == 0 { b'a' } else { b'b' })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut rules = Rules::parse(
+            "\
+            0: 8 11\n8: 42\n11: 42 31\n\
+            42: 2 2 | 3\n31: 3 2 | 2 3\n2: 4 | 5\n3: 4 4 | 5\n\
+            4: \"a\"\n5: \"b\"\
+            ",
+        )
+        .unwrap();
+        let replacements = [
+            "",
+            "8: 42 | 42 8",
+            // mutually recursive, still right-linear
+            "8: 42 | 42 12\n12: 3 8 | 31",
+        ];
+        for replacement in &replacements {
+            rules.replace(replacement).unwrap();
+            let grammar = Grammar::new(&rules).unwrap();
+            let dfa = compile_dfa("test", &grammar).unwrap();
+            for message in &messages {
+                assert_eq!(dfa.is_match(message), grammar.is_match(0, message));
+            }
+        }
+
+        // not regular
+        rules.replace(V2_REPLACEMENTS).unwrap();
+        assert!(compile_dfa("test", &Grammar::new(&rules).unwrap()).is_err());
+
+        // a+ needs a start, accepting, and dead state
+        let rules = Rules::parse("0: 1 | 1 0\n1: \"a\"").unwrap();
+        let dfa = compile_dfa("test", &Grammar::new(&rules).unwrap()).unwrap();
+        assert_eq!(dfa.num_states(), 3);
+        assert!(dfa.is_match(b"aaa"));
+        assert!(!dfa.is_match(b""));
+        assert!(!dfa.is_match(b"ab"));
+
+        let rules = Rules::parse("0: \"c\"").unwrap();
+        assert!(compile_dfa("test", &Grammar::new(&rules).unwrap()).is_err());
     }
 }
@@ -1,16 +1,20 @@
-use anyhow::{Context, Result};
-use arrayvec::ArrayVec;
+use anyhow::{anyhow, Context, Result};
 use either::Either;
 use fixedbitset::FixedBitSet;
 use petgraph::{
+    algo::{dominators::simple_fast, tarjan_scc},
     data::{Element, FromElements},
     graph::DiGraph,
-    visit::{Dfs, Reversed, Walker},
+    visit::{Dfs, EdgeRef, Reversed},
+    Direction,
 };
 use std::{
+    convert::TryFrom,
     fmt, fs,
     iter::{self, ExactSizeIterator},
+    num::ParseIntError,
     ops::Range,
+    str::FromStr,
 };
 
 type Leaders = FixedBitSet;
@@ -18,25 +22,73 @@ type BasicBlock = Range<usize>;
 type BasicBlockGraph = DiGraph<(), (), usize>;
 type BlockConnectivity = FixedBitSet;
 
-enum Instr {
-    Acc(i16),
-    Jmp(i16),
-    Nop(i16),
+// The integer type used for instruction operands and the accumulator. The
+// width is chosen per program run (i64 by default); all arithmetic is checked
+// so narrow widths trap instead of silently wrapping.
+trait Word:
+    Copy + Default + Eq + Ord + fmt::Debug + fmt::Display + FromStr<Err = ParseIntError>
+{
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    fn from_usize(n: usize) -> Option<Self>;
+    fn to_usize(self) -> Option<usize>;
 }
 
-impl Instr {
-    fn from_str(s: &str) -> Self {
+macro_rules! impl_word {
+    ($($t:ty),*) => {$(
+        impl Word for $t {
+            #[inline]
+            fn checked_add(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_add(self, rhs)
+            }
+
+            #[inline]
+            fn from_usize(n: usize) -> Option<Self> {
+                <$t>::try_from(n).ok()
+            }
+
+            #[inline]
+            fn to_usize(self) -> Option<usize> {
+                usize::try_from(self).ok()
+            }
+        }
+    )*};
+}
+
+impl_word!(i16, i32, i64, i128);
+
+// Compute `idx + off`, returning None if the result is negative or doesn't fit
+// in the word type. The result may still be past the end of the program.
+fn jump_target<W: Word>(idx: usize, off: W) -> Option<usize> {
+    W::from_usize(idx)?.checked_add(off)?.to_usize()
+}
+
+enum Instr<W = i64> {
+    Acc(W),
+    Jmp(W),
+    Nop(W),
+}
+
+impl<W: Word> FromStr for Instr<W> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
         use Instr::*;
-        let (instr, val) = s.split_at(3);
-        let val = val[1..].parse::<i16>().expect("Invalid value");
+        let mut parts = s.splitn(2, ' ');
+        let instr = parts.next().unwrap_or("");
+        let val = parts.next().context("Missing operand")?;
+        let val = val
+            .parse::<W>()
+            .with_context(|| format!("Invalid operand: '{}'", val))?;
         match instr {
-            "acc" => Acc(val),
-            "jmp" => Jmp(val),
-            "nop" => Nop(val),
-            _ => panic!("Invalid instruction"),
+            "acc" => Ok(Acc(val)),
+            "jmp" => Ok(Jmp(val)),
+            "nop" => Ok(Nop(val)),
+            _ => Err(anyhow!("Invalid instruction: '{}'", instr)),
         }
     }
+}
 
+impl<W: Word> Instr<W> {
     const fn is_jmp(&self) -> bool {
         matches!(self, Self::Jmp(_))
     }
@@ -55,7 +107,7 @@ impl Instr {
     }
 }
 
-impl fmt::Display for Instr {
+impl<W: Word> fmt::Display for Instr<W> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Instr::*;
         let (instr, val) = match self {
@@ -67,43 +119,279 @@ impl fmt::Display for Instr {
     }
 }
 
-impl fmt::Debug for Instr {
+impl<W: Word> fmt::Debug for Instr<W> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self)
     }
 }
 
-// Evaluate program instructions, returning Ok(acc) if the program terminates
-// and Err(acc) if the program is about to execute an instruction for another
-// time (i.e., infinite loop).
-fn eval(instrs: &[Instr]) -> Result<i16, i16> {
+// Abnormal ways a program can stop, other than terminating or looping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Trap {
+    // The jmp at `ip` targets an instruction outside of [0, len].
+    OutOfBounds { ip: usize },
+    // The acc at `ip` overflowed the accumulator.
+    Overflow { ip: usize },
+    // Executed `steps` instructions without terminating or looping.
+    StepLimit { steps: usize },
+}
+
+// How a program stopped, along with the acc value at that point.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Exit<W> {
+    // The program jumped to the instruction right after the last instruction.
+    Terminated(W),
+    // The program was about to execute an instruction for another time (i.e.,
+    // infinite loop).
+    Looped(W),
+    Trap(Trap, W),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum EvalOptions {
+    // Report a loop as soon as an instruction is about to execute again.
+    // Optionally trap after executing `step_limit` instructions.
+    StopOnLoop { step_limit: Option<usize> },
+    // Keep executing already-visited instructions instead of reporting a loop,
+    // e.g., to profile the loop body. A looping program would never stop
+    // otherwise, so the step limit is required here.
+    AllowRevisits { step_limit: usize },
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        Self::StopOnLoop { step_limit: None }
+    }
+}
+
+impl EvalOptions {
+    fn step_limit(&self) -> Option<usize> {
+        match *self {
+            Self::StopOnLoop { step_limit } => step_limit,
+            Self::AllowRevisits { step_limit } => Some(step_limit),
+        }
+    }
+
+    fn allow_revisits(&self) -> bool {
+        matches!(self, Self::AllowRevisits { .. })
+    }
+}
+
+fn eval<W: Word>(instrs: &[Instr<W>]) -> Exit<W> {
+    eval_with(instrs, &EvalOptions::default())
+}
+
+// Evaluate program instructions until the program terminates, loops, or traps.
+fn eval_with<W: Word>(instrs: &[Instr<W>], opts: &EvalOptions) -> Exit<W> {
+    eval_observed(instrs, opts, |_, _, _| ()).0
+}
+
+// Like `eval_with`, but calls `on_step(ip, acc_before, acc_after)` after each
+// executed instruction. Also returns the ip the program stopped at.
+fn eval_observed<W, F>(instrs: &[Instr<W>], opts: &EvalOptions, mut on_step: F) -> (Exit<W>, usize)
+where
+    W: Word,
+    F: FnMut(usize, W, W),
+{
     let mut visited_instrs = FixedBitSet::with_capacity(instrs.len());
 
-    let mut ip: i16 = 0;
-    let mut acc: i16 = 0;
-    let terminal_idx = instrs.len() as i16;
+    let mut ip: usize = 0;
+    let mut acc = W::default();
+    let mut steps: usize = 0;
+    let terminal_idx = instrs.len();
 
     loop {
         // Terminated
         if ip == terminal_idx {
-            return Ok(acc);
+            return (Exit::Terminated(acc), ip);
         }
 
-        let instr = &instrs[ip as usize];
-        if visited_instrs[ip as usize] {
+        if visited_instrs[ip] && !opts.allow_revisits() {
             // Hit an already-visited instruction: looping!
-            return Err(acc);
+            return (Exit::Looped(acc), ip);
         } else {
-            visited_instrs.insert(ip as usize);
+            visited_instrs.insert(ip);
         }
 
+        if opts.step_limit() == Some(steps) {
+            return (Exit::Trap(Trap::StepLimit { steps }, acc), ip);
+        }
+        steps += 1;
+
         // Evaluate instruction
-        match instr {
-            Instr::Acc(amt) => acc += amt,
-            Instr::Jmp(off) => ip += off - 1,
-            Instr::Nop(_) => (),
+        let acc_before = acc;
+        let next_ip = match &instrs[ip] {
+            Instr::Acc(amt) => match acc.checked_add(*amt) {
+                Some(next_acc) => {
+                    acc = next_acc;
+                    ip + 1
+                }
+                None => return (Exit::Trap(Trap::Overflow { ip }, acc), ip),
+            },
+            Instr::Jmp(off) => match jump_target(ip, *off) {
+                Some(target) if target <= terminal_idx => target,
+                _ => return (Exit::Trap(Trap::OutOfBounds { ip }, acc), ip),
+            },
+            Instr::Nop(_) => ip + 1,
+        };
+        on_step(ip, acc_before, acc);
+        ip = next_ip;
+    }
+}
+
+// One executed instruction in a trace.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Step<W> {
+    ip: usize,
+    acc_before: W,
+    acc_after: W,
+}
+
+// Execution profile of a single program run: per-instruction hit counts and
+// (optionally) the full trace of executed instructions.
+#[derive(Debug)]
+struct Profile<W> {
+    hits: Vec<usize>,
+    trace: Option<Vec<Step<W>>>,
+    exit: Exit<W>,
+    // The instruction the program stopped at, e.g., the first instruction
+    // executed twice for a looping program.
+    exit_ip: usize,
+}
+
+impl<W: Word> Profile<W> {
+    fn new(instrs: &[Instr<W>], opts: &EvalOptions, record_trace: bool) -> Self {
+        let mut hits = vec![0; instrs.len()];
+        let mut trace = if record_trace { Some(Vec::new()) } else { None };
+
+        let (exit, exit_ip) = eval_observed(instrs, opts, |ip, acc_before, acc_after| {
+            hits[ip] += 1;
+            if let Some(trace) = &mut trace {
+                trace.push(Step {
+                    ip,
+                    acc_before,
+                    acc_after,
+                });
+            }
+        });
+
+        Self {
+            hits,
+            trace,
+            exit,
+            exit_ip,
         }
-        ip += 1;
+    }
+
+    // The set of executed instructions (same as `eval`'s visited set).
+    fn instr_coverage(&self) -> FixedBitSet {
+        self.hits
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &hits)| if hits > 0 { Some(idx) } else { None })
+            .collect()
+    }
+
+    // Returns a bitset which maps basic block index -> true if any instruction
+    // in that basic block was executed.
+    fn block_coverage(&self, basic_blocks: &[BasicBlock]) -> BlockConnectivity {
+        basic_blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(block_idx, block)| {
+                if self.hits[block.clone()].iter().any(|&hits| hits > 0) {
+                    Some(block_idx)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+// Displays a trace as a table of (step, ip, instr, acc before, acc after).
+struct TraceTable<'a, W> {
+    instrs: &'a [Instr<W>],
+    trace: &'a [Step<W>],
+}
+
+impl<'a, W: Word> fmt::Display for TraceTable<'a, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>8} {:>6}  {:<12} {:>12} {:>12}",
+            "step", "ip", "instr", "acc", "acc'"
+        )?;
+        for (step_idx, step) in self.trace.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>8} {:>6}  {:<12} {:>12} {:>12}",
+                step_idx,
+                step.ip,
+                self.instrs[step.ip].to_string(),
+                step.acc_before,
+                step.acc_after,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Displays the program annotated with basic blocks, hit counts, and where the
+// program stopped, followed by a coverage summary.
+struct Listing<'a, W> {
+    instrs: &'a [Instr<W>],
+    basic_blocks: &'a [BasicBlock],
+    profile: &'a Profile<W>,
+}
+
+impl<'a, W: Word> fmt::Display for Listing<'a, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let block_coverage = self.profile.block_coverage(self.basic_blocks);
+
+        for (block_idx, block) in self.basic_blocks.iter().enumerate() {
+            let covered = if block_coverage.contains(block_idx) {
+                ""
+            } else {
+                "  (not covered)"
+            };
+            writeln!(
+                f,
+                "block {}: [{}, {}){}",
+                block_idx, block.start, block.end, covered
+            )?;
+
+            for idx in block.clone() {
+                let marker = if idx == self.profile.exit_ip {
+                    ">"
+                } else {
+                    " "
+                };
+                writeln!(
+                    f,
+                    "{} {:>6} {:>8}  {}",
+                    marker, idx, self.profile.hits[idx], self.instrs[idx]
+                )?;
+            }
+        }
+
+        writeln!(
+            f,
+            "exit: {:?} at ip {}",
+            self.profile.exit, self.profile.exit_ip
+        )?;
+        writeln!(
+            f,
+            "instrs covered: {}/{}",
+            self.profile.instr_coverage().count_ones(..),
+            self.instrs.len()
+        )?;
+        write!(
+            f,
+            "blocks covered: {}/{}",
+            block_coverage.count_ones(..),
+            self.basic_blocks.len()
+        )
     }
 }
 
@@ -114,45 +402,31 @@ fn eval(instrs: &[Instr]) -> Result<i16, i16> {
 //   3. an instruction immediately after a jmp
 // include_nop will interpret nops as jmps for the purposes of computing leaders
 // (and therefore also basic blocks).
-fn leaders(instrs: &[Instr], include_nop: bool) -> Leaders {
+fn leaders<W: Word>(instrs: &[Instr<W>], include_nop: bool) -> Leaders {
     let mut leaders = Leaders::with_capacity(instrs.len());
 
     for (idx, instr) in instrs.iter().enumerate() {
-        let idx = idx as i16;
-
         // First instruction is a leader
         if idx == 0 {
             leaders.insert(0);
         } else {
-            let prev_instr = &instrs[(idx - 1) as usize];
+            let prev_instr = &instrs[idx - 1];
 
             if prev_instr.is_jmp() || (include_nop && prev_instr.is_nop()) {
                 // If previous instruction is a jmp, then we're a leader
-                leaders.insert(idx as usize);
+                leaders.insert(idx);
             }
         }
 
         // If we're a jmp, then our target is a leader
         let maybe_target = match instr {
-            Instr::Jmp(off) => {
-                if idx + off < instrs.len() as i16 {
-                    Some(idx + off)
-                } else {
-                    None
-                }
-            }
-            Instr::Nop(off) if include_nop => {
-                if idx + off < instrs.len() as i16 {
-                    Some(idx + off)
-                } else {
-                    None
-                }
-            }
+            Instr::Jmp(off) => jump_target(idx, *off),
+            Instr::Nop(off) if include_nop => jump_target(idx, *off),
             _ => None,
         };
 
-        if let Some(target) = maybe_target {
-            leaders.insert(target as usize);
+        if let Some(target) = maybe_target.filter(|&target| target < instrs.len()) {
+            leaders.insert(target);
         }
     }
 
@@ -185,8 +459,8 @@ fn basic_block_map(basic_blocks: &[BasicBlock]) -> impl Iterator<Item = usize> +
 // two kinds of edges: fallthrough edges, where the previous basic block's end instruction
 // is not a jmp (e.g., it's a target of a jmp or a nop), and jmp edges, where the
 // end of a basic block is a jmp targeting another basic block.
-fn basic_block_graph(
-    instrs: &[Instr],
+fn basic_block_graph<W: Word>(
+    instrs: &[Instr<W>],
     basic_blocks: &[BasicBlock],
     basic_block_map: &[usize],
 ) -> BasicBlockGraph {
@@ -214,9 +488,9 @@ fn basic_block_graph(
 
             // 2: end of basic block is a jmp: curr bb -> target bb
             let jmp_iter = if let Instr::Jmp(off) = &instrs[end_idx] {
-                let target_idx = ((end_idx as i16) + *off) as usize;
+                let maybe_target = jump_target(end_idx, *off);
 
-                if target_idx < instrs.len() {
+                if let Some(target_idx) = maybe_target.filter(|&idx| idx < instrs.len()) {
                     let target_block_idx = basic_block_map[target_idx];
                     Either::Left(iter::once(Element::Edge {
                         source: basic_block_idx,
@@ -236,70 +510,211 @@ fn basic_block_graph(
     BasicBlockGraph::from_elements(elements)
 }
 
-// Determine which basic blocks are connected to the source (first basic block
-// containing the program start instruction). In this case, "connected" means
-// executing the program from the beginning will eventually reach this basic block.
+// Determine which basic blocks are connected to the terminal, i.e., blocks that
+// fall through or jump to exactly one past the last instruction, along with the
+// blocks that lead to them. In this case, "connected" means if we enter a
+// connected basic block, then the program execution will eventually terminate.
 //
 // Returns a bitset which maps basic block index -> true if that basic block is
-// connected to source.
-fn source_connectivity(basic_block_graph: &BasicBlockGraph) -> BlockConnectivity {
-    let mut connectivity = FixedBitSet::with_capacity(basic_block_graph.node_count());
-    let source_idx = 0;
+// connected to terminal.
+fn terminal_connectivity<W: Word>(
+    instrs: &[Instr<W>],
+    basic_blocks: &[BasicBlock],
+    basic_block_graph: &BasicBlockGraph,
+) -> BlockConnectivity {
+    let mut connectivity = FixedBitSet::with_capacity(basic_blocks.len());
+    let mut dfs = Dfs::empty(basic_block_graph);
 
-    for node in Dfs::new(&basic_block_graph, source_idx.into()).iter(&basic_block_graph) {
-        connectivity.insert(node.index());
+    for (block_idx, block) in basic_blocks.iter().enumerate() {
+        let end_idx = block.end - 1;
+        let next_idx = match &instrs[end_idx] {
+            Instr::Jmp(off) => jump_target(end_idx, *off),
+            _ => Some(block.end),
+        };
+
+        if next_idx == Some(instrs.len()) {
+            dfs.move_to(block_idx.into());
+            while let Some(node) = dfs.next(Reversed(basic_block_graph)) {
+                connectivity.insert(node.index());
+            }
+        }
     }
 
     connectivity
 }
 
-// Determine which basic blocks are connected to the terminal (last basic block
-// containing the program end). In this case, "connected" means if we enter a
-// connected basic block, then the program execution will eventually terminate.
-//
-// Returns a bitset which maps basic block index -> true if that basic block is
-// connected to terminal.
-fn terminal_connectivity(basic_block_graph: &BasicBlockGraph) -> BlockConnectivity {
-    let num_blocks = basic_block_graph.node_count();
-    let mut connectivity = FixedBitSet::with_capacity(num_blocks);
-    let terminal_idx = num_blocks - 1;
-
-    for node in Dfs::new(&basic_block_graph, terminal_idx.into()).iter(Reversed(&basic_block_graph))
-    {
-        connectivity.insert(node.index());
+// Determine which basic blocks are reachable from the first basic block.
+fn reachable_blocks(basic_block_graph: &BasicBlockGraph) -> BlockConnectivity {
+    let mut reachable = FixedBitSet::with_capacity(basic_block_graph.node_count());
+    let mut dfs = Dfs::new(basic_block_graph, 0.into());
+    while let Some(node) = dfs.next(basic_block_graph) {
+        reachable.insert(node.index());
     }
+    reachable
+}
 
-    connectivity
+// A natural loop: the blocks dominated by `header` that can reach one of the
+// back edges (latch -> header) without going through `header`.
+#[derive(Debug)]
+struct NaturalLoop {
+    header: usize,
+    latches: Vec<usize>,
+    body: FixedBitSet,
+    // Index of the smallest loop strictly containing this one.
+    parent: Option<usize>,
+    depth: usize,
 }
 
-// Return true if the basic block graph is connected from source -> terminal.
-fn is_connected(basic_block_graph: &BasicBlockGraph) -> bool {
-    let num_blocks = basic_block_graph.node_count();
-    let source_idx = 0;
-    let terminal_idx = num_blocks - 1;
+// Dominator tree, natural loop nest, and strongly connected components of a
+// basic block graph, rooted at the first basic block.
+#[derive(Debug)]
+struct LoopAnalysis {
+    // Immediate dominator of each block; None for the root and for blocks
+    // unreachable from the root.
+    idom: Vec<Option<usize>>,
+    // Edges (latch, header) where the header dominates the latch.
+    back_edges: Vec<(usize, usize)>,
+    // Natural loops, merged by header and ordered outermost first.
+    loops: Vec<NaturalLoop>,
+    // Non-trivial SCCs, i.e., with more than one block or a self-loop. Any
+    // infinite loop must stay inside one of these.
+    sccs: Vec<Vec<usize>>,
+    // Map from block index -> containing non-trivial SCC index.
+    scc_map: Vec<Option<usize>>,
+}
+
+impl LoopAnalysis {
+    fn new(basic_block_graph: &BasicBlockGraph) -> Self {
+        let num_blocks = basic_block_graph.node_count();
+        let dominators = simple_fast(basic_block_graph, 0.into());
+
+        let idom = (0..num_blocks)
+            .map(|block_idx| {
+                dominators
+                    .immediate_dominator(block_idx.into())
+                    .map(|node| node.index())
+            })
+            .collect::<Vec<_>>();
+
+        let dominates = |header: usize, block_idx: usize| {
+            dominators
+                .dominators(block_idx.into())
+                .into_iter()
+                .flatten()
+                .any(|node| node.index() == header)
+        };
+
+        let mut back_edges = basic_block_graph
+            .edge_references()
+            .map(|edge| (edge.source().index(), edge.target().index()))
+            .filter(|&(latch, header)| dominates(header, latch))
+            .collect::<Vec<_>>();
+        back_edges.sort_unstable();
+
+        let mut loops = Vec::<NaturalLoop>::new();
+        for &(latch, header) in &back_edges {
+            let idx = match loops.iter().position(|l| l.header == header) {
+                Some(idx) => idx,
+                None => {
+                    let mut body = FixedBitSet::with_capacity(num_blocks);
+                    body.insert(header);
+                    loops.push(NaturalLoop {
+                        header,
+                        latches: Vec::new(),
+                        body,
+                        parent: None,
+                        depth: 0,
+                    });
+                    loops.len() - 1
+                }
+            };
+            let natural_loop = &mut loops[idx];
+            natural_loop.latches.push(latch);
+
+            // Walk backwards from the latch until we hit the header.
+            let mut stack = vec![latch];
+            while let Some(block_idx) = stack.pop() {
+                let is_reachable = block_idx == 0 || idom[block_idx].is_some();
+                if !is_reachable || natural_loop.body.put(block_idx) {
+                    continue;
+                }
+                stack.extend(
+                    basic_block_graph
+                        .neighbors_directed(block_idx.into(), Direction::Incoming)
+                        .map(|node| node.index()),
+                );
+            }
+        }
+
+        // Outermost loops first, so a loop's parent is always before it.
+        loops.sort_by_key(|l| std::cmp::Reverse(l.body.count_ones(..)));
+        for idx in 0..loops.len() {
+            let parent = (0..idx).rev().find(|&parent_idx| {
+                let parent = &loops[parent_idx];
+                parent.header != loops[idx].header && loops[idx].body.is_subset(&parent.body)
+            });
+            loops[idx].parent = parent;
+            loops[idx].depth = parent.map_or(0, |parent_idx| loops[parent_idx].depth + 1);
+        }
+
+        let mut scc_map = vec![None; num_blocks];
+        let sccs = tarjan_scc(basic_block_graph)
+            .into_iter()
+            .map(|scc| {
+                let mut scc = scc.into_iter().map(|node| node.index()).collect::<Vec<_>>();
+                scc.sort_unstable();
+                scc
+            })
+            .filter(|scc| {
+                scc.len() > 1
+                    || basic_block_graph
+                        .find_edge(scc[0].into(), scc[0].into())
+                        .is_some()
+            })
+            .collect::<Vec<_>>();
+        for (scc_idx, scc) in sccs.iter().enumerate() {
+            for &block_idx in scc {
+                scc_map[block_idx] = Some(scc_idx);
+            }
+        }
 
-    for node in Dfs::new(&basic_block_graph, source_idx.into()).iter(&basic_block_graph) {
-        if node.index() == terminal_idx {
-            return true;
+        Self {
+            idom,
+            back_edges,
+            loops,
+            sccs,
+            scc_map,
         }
     }
 
-    false
+    // The innermost natural loop containing the block, if any.
+    fn innermost_loop(&self, block_idx: usize) -> Option<usize> {
+        (0..self.loops.len())
+            .rev()
+            .find(|&loop_idx| self.loops[loop_idx].body.contains(block_idx))
+    }
 }
 
-// Find the single jmp or nop instruction that when "repaired" will allow the
-// program to terminate.
+// Find the jmp or nop instructions that when "repaired" will allow the program
+// to terminate. Empty if the program already terminates.
 //
 // Strategy:
 //
-//  1. find leaders
+//  1. find leaders, treating nops like jmps, so every jmp and nop ends a block
 //  2. basic blocks from leaders
 //  3. basic block graph
-//  4. source connectivity
+//  4. reachability from the first block: since every block has exactly one
+//     successor, these are the blocks executed on the way into (and around)
+//     the loop the program gets stuck in
 //  5. terminal connectivity
-//  6. walk source-connected basic block graph to find repair that connects
-//     terminal-connected basic block graph.
-fn find_repair(instrs: &[Instr]) -> Option<usize> {
+//  6. keep the executed jmps and nops whose flipped successor is
+//     terminal-connected.
+//
+// Every block has exactly one successor, so flipping an instruction that never
+// executes can't change anything, and the flipped successor of an executed
+// instruction can't lead back to it (otherwise the unrepaired program would
+// terminate too). So these are exactly the repairs that work.
+fn repair_candidates<W: Word>(instrs: &[Instr<W>]) -> Vec<usize> {
     let include_nop = true;
     let leaders = leaders(instrs, include_nop);
     let leader_indices = leaders.ones().collect::<Vec<_>>();
@@ -309,95 +724,413 @@ fn find_repair(instrs: &[Instr]) -> Option<usize> {
     let basic_block_map = basic_block_map(&basic_blocks).collect::<Vec<_>>();
     let basic_block_graph = basic_block_graph(instrs, &basic_blocks, &basic_block_map);
 
+    let terminal_connectivity = terminal_connectivity(instrs, &basic_blocks, &basic_block_graph);
+
     // Already connected; no repair needed.
-    if is_connected(&basic_block_graph) {
-        return None;
+    if terminal_connectivity.contains(0) {
+        return Vec::new();
     }
 
-    let source_connectivity = source_connectivity(&basic_block_graph);
-    let terminal_connectivity = terminal_connectivity(&basic_block_graph);
+    let reachable = reachable_blocks(&basic_block_graph);
+    let terminates = |idx: usize| {
+        idx == terminal_idx
+            || (idx < terminal_idx && terminal_connectivity.contains(basic_block_map[idx]))
+    };
 
-    // Objective: Find a leader or exit instruction in a source-connected basic
-    // block that, when "repaired", will connect source -> terminal.
-    //
-    // We only need to check adding edges since removing edges can never improve
-    // connectivity from source -> terminal.
+    basic_blocks
+        .iter()
+        .enumerate()
+        .filter(|&(block_idx, _)| reachable.contains(block_idx))
+        .filter_map(|(_, block)| {
+            let end_idx = block.end - 1;
+            let flipped_next_idx = match &instrs[end_idx] {
+                // Jmp -> Nop: fall through to the next block
+                Instr::Jmp(_) => Some(block.end),
+                // Nop -> Jmp: jump to the target
+                Instr::Nop(off) => jump_target(end_idx, *off),
+                Instr::Acc(_) => None,
+            };
+            match flipped_next_idx {
+                Some(idx) if terminates(idx) => Some(end_idx),
+                _ => None,
+            }
+        })
+        .collect()
+}
 
-    let source_blocks_and_instrs = source_connectivity.ones().flat_map(|block_idx| {
-        let block = &basic_blocks[block_idx];
-        let leader_idx = block.start;
-        let end_idx = block.end - 1;
+// Find the single jmp or nop instruction that when "repaired" will allow the
+// program to terminate.
+fn find_repair<W: Word>(instrs: &[Instr<W>]) -> Option<usize> {
+    repair_candidates(instrs).first().copied()
+}
 
-        let mut instr_idxs = ArrayVec::<[(usize, usize); 2]>::new();
-        instr_idxs.push((block_idx, leader_idx));
+// Where control goes after executing a compiled basic block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Transition {
+    Block(usize),
+    Terminal,
+    // The jmp at the end of the block targets an instruction outside [0, len].
+    OutOfBounds,
+}
 
-        if leader_idx != end_idx {
-            instr_idxs.push((block_idx, end_idx));
+// A basic block fused into a single acc delta and successor transition.
+#[derive(Clone, Debug)]
+struct CompiledBlock<W> {
+    instrs: BasicBlock,
+    // Sum of all acc instructions in the block, or None if some prefix sum
+    // doesn't fit in the word type (we then always step instruction-wise).
+    acc_delta: Option<W>,
+    // Smallest and largest acc prefix sums (including the empty prefix). If
+    // adding both to the current acc doesn't overflow, then no acc instruction
+    // in the block can overflow.
+    min_prefix: W,
+    max_prefix: W,
+    next: Transition,
+}
+
+// A program compiled to basic blocks, so evaluation runs per block rather
+// than per instruction. Gives identical results to `eval_with`.
+struct Compiled<'a, W> {
+    instrs: &'a [Instr<W>],
+    blocks: Vec<CompiledBlock<W>>,
+}
+
+impl<'a, W: Word> Compiled<'a, W> {
+    fn new(instrs: &'a [Instr<W>]) -> Self {
+        if instrs.is_empty() {
+            return Self {
+                instrs,
+                blocks: Vec::new(),
+            };
         }
 
-        instr_idxs
-    });
-
-    for (block_idx, instr_idx) in source_blocks_and_instrs {
-        match &instrs[instr_idx] {
-            Instr::Jmp(_) => {
-                // Jmp -> Nop
-                // Jmp is always the end of a basic block
-                //   ==> Add fallthrough edge
-                //   ==> Remove jmp edge : cannot improve connectivity
-
-                // Adding the fallthrough edge connects source -> terminal: we're done!
-                // source_connectivity.contains(block_idx) is implied
-                if terminal_connectivity.contains(block_idx + 1) {
-                    return Some(instr_idx);
+        let leaders = leaders(instrs, false);
+        let leader_indices = leaders.ones().collect::<Vec<_>>();
+        let basic_blocks = basic_blocks(&leader_indices, instrs.len()).collect::<Vec<_>>();
+        let basic_block_map = basic_block_map(&basic_blocks).collect::<Vec<_>>();
+
+        let blocks = basic_blocks
+            .iter()
+            .enumerate()
+            .map(|(block_idx, block)| {
+                let mut acc_delta = Some(W::default());
+                let mut min_prefix = W::default();
+                let mut max_prefix = W::default();
+
+                for instr in &instrs[block.clone()] {
+                    if let (Instr::Acc(amt), Some(delta)) = (instr, acc_delta) {
+                        acc_delta = delta.checked_add(*amt);
+                        if let Some(delta) = acc_delta {
+                            min_prefix = min_prefix.min(delta);
+                            max_prefix = max_prefix.max(delta);
+                        }
+                    }
+                }
+
+                let end_idx = block.end - 1;
+                let fallthrough_idx = match &instrs[end_idx] {
+                    Instr::Jmp(off) => jump_target(end_idx, *off),
+                    _ => Some(block.end),
+                };
+                let next = match fallthrough_idx {
+                    Some(idx) if idx == instrs.len() => Transition::Terminal,
+                    Some(idx) if idx == block.end => Transition::Block(block_idx + 1),
+                    Some(idx) if idx < instrs.len() => Transition::Block(basic_block_map[idx]),
+                    _ => Transition::OutOfBounds,
+                };
+
+                CompiledBlock {
+                    instrs: block.clone(),
+                    acc_delta,
+                    min_prefix,
+                    max_prefix,
+                    next,
                 }
+            })
+            .collect();
+
+        Self { instrs, blocks }
+    }
+
+    fn eval(&self) -> Exit<W> {
+        self.eval_with(&EvalOptions::default())
+    }
+
+    fn eval_with(&self, opts: &EvalOptions) -> Exit<W> {
+        if self.blocks.is_empty() {
+            return Exit::Terminated(W::default());
+        }
+
+        let mut visited_blocks = FixedBitSet::with_capacity(self.blocks.len());
+
+        let mut block_idx: usize = 0;
+        let mut acc = W::default();
+        let mut steps: usize = 0;
+
+        loop {
+            // Blocks are only ever entered at their leader, so revisiting any
+            // instruction means revisiting its whole block.
+            if visited_blocks[block_idx] && !opts.allow_revisits() {
+                return Exit::Looped(acc);
+            } else {
+                visited_blocks.insert(block_idx);
             }
-            Instr::Nop(off) => {
-                // Nop -> Jmp
-                //   ==> Add jmp edge
-                // If fallthrough source
-                //   ==> Remove fallthrough edge : cannot improve connectivity
-
-                let target_idx = ((instr_idx as i16) + *off) as usize;
-                let target_block_idx = basic_block_map[target_idx];
-
-                // Adding this edge connects source -> terminal: we're done!
-                // source_connectivity.contains(block_idx) is implied
-                if terminal_connectivity.contains(target_block_idx) {
-                    return Some(instr_idx);
+
+            let block = &self.blocks[block_idx];
+            let num_instrs = block.instrs.len();
+
+            // Fast path: the whole block executes without trapping.
+            let fused_acc = block.acc_delta.and_then(|delta| {
+                let hits_step_limit =
+                    matches!(opts.step_limit(), Some(limit) if steps + num_instrs > limit);
+                if !hits_step_limit {
+                    acc.checked_add(block.min_prefix)?;
+                    acc.checked_add(block.max_prefix)?;
+                    acc.checked_add(delta)
+                } else {
+                    None
+                }
+            });
+
+            match fused_acc {
+                Some(next_acc) => {
+                    acc = next_acc;
+                    steps += num_instrs;
+                }
+                // Slow path: step through the block to find exactly where it traps.
+                None => {
+                    for ip in block.instrs.clone() {
+                        if opts.step_limit() == Some(steps) {
+                            return Exit::Trap(Trap::StepLimit { steps }, acc);
+                        }
+                        steps += 1;
+
+                        if let Instr::Acc(amt) = &self.instrs[ip] {
+                            match acc.checked_add(*amt) {
+                                Some(next_acc) => acc = next_acc,
+                                None => return Exit::Trap(Trap::Overflow { ip }, acc),
+                            }
+                        }
+                    }
+                }
+            }
+
+            match block.next {
+                Transition::Block(next_block_idx) => block_idx = next_block_idx,
+                Transition::Terminal => return Exit::Terminated(acc),
+                Transition::OutOfBounds => {
+                    let ip = block.instrs.end - 1;
+                    return Exit::Trap(Trap::OutOfBounds { ip }, acc);
                 }
             }
-            Instr::Acc(_) => (),
         }
     }
+}
+
+fn parse_instructions<W: Word>(program: &str) -> Result<Vec<Instr<W>>> {
+    program
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            line.parse::<Instr<W>>()
+                .with_context(|| format!("line {}: '{}'", idx + 1, line))
+        })
+        .collect()
+}
 
-    Some(0)
+#[derive(Clone, Copy, Debug)]
+struct RunOptions {
+    width: u32,
+    trace: bool,
+    profile: bool,
+    compiled: bool,
+    cfg: bool,
+    // Options for the traced/profiled runs.
+    report_opts: EvalOptions,
 }
 
-fn parse_instructions(program: &str) -> Vec<Instr> {
-    program.lines().map(Instr::from_str).collect::<Vec<_>>()
+fn eval_run<W: Word>(instrs: &[Instr<W>], run_opts: RunOptions) -> Exit<W> {
+    if run_opts.compiled {
+        let compiled = time!("compile", Compiled::new(instrs));
+        time!("eval compiled", compiled.eval())
+    } else {
+        time!("eval", eval(instrs))
+    }
 }
 
-pub fn run(args: &[&str]) -> Result<()> {
-    let input = fs::read_to_string(args[0]).context("Failed to read file")?;
-    let mut instrs = parse_instructions(&input);
+// Print the trace table and/or annotated listing for a run of the program.
+fn report<W: Word>(instrs: &[Instr<W>], run_opts: RunOptions) {
+    if !run_opts.trace && !run_opts.profile {
+        return;
+    }
+
+    let profile = Profile::new(instrs, &run_opts.report_opts, run_opts.trace);
+
+    if let Some(trace) = &profile.trace {
+        println!("{}", TraceTable { instrs, trace });
+    }
+
+    if run_opts.profile {
+        let leaders = leaders(instrs, false);
+        let leader_indices = leaders.ones().collect::<Vec<_>>();
+        let basic_blocks = basic_blocks(&leader_indices, instrs.len()).collect::<Vec<_>>();
+
+        let listing = Listing {
+            instrs,
+            basic_blocks: &basic_blocks,
+            profile: &profile,
+        };
+        println!("{}", listing);
+    }
+}
+
+// Print the dominator tree, loop nest, and SCCs of the program's control flow
+// graph, along with the loop `eval` gets stuck in and whether the repair
+// instruction breaks it.
+fn report_cfg<W: Word>(instrs: &[Instr<W>], repair_instr_idx: usize) {
+    let leaders = leaders(instrs, false);
+    let leader_indices = leaders.ones().collect::<Vec<_>>();
+    let basic_blocks = basic_blocks(&leader_indices, instrs.len()).collect::<Vec<_>>();
+    let basic_block_map = basic_block_map(&basic_blocks).collect::<Vec<_>>();
+    let basic_block_graph = basic_block_graph(instrs, &basic_blocks, &basic_block_map);
+    let analysis = LoopAnalysis::new(&basic_block_graph);
+
+    println!("dominator tree:");
+    for (block_idx, idom) in analysis.idom.iter().enumerate() {
+        if let Some(idom) = idom {
+            println!("  {} -> {} {:?}", idom, block_idx, basic_blocks[block_idx]);
+        }
+    }
+
+    println!("back edges: {:?}", analysis.back_edges);
+
+    println!("natural loops:");
+    for (loop_idx, natural_loop) in analysis.loops.iter().enumerate() {
+        println!(
+            "  {}{}: header {}, latches {:?}, parent {:?}, blocks {:?}",
+            "  ".repeat(natural_loop.depth),
+            loop_idx,
+            natural_loop.header,
+            natural_loop.latches,
+            natural_loop.parent,
+            natural_loop.body.ones().collect::<Vec<_>>(),
+        );
+    }
+
+    println!("sccs:");
+    for (scc_idx, scc) in analysis.sccs.iter().enumerate() {
+        println!("  {}: blocks {:?}", scc_idx, scc);
+    }
+
+    let profile = Profile::new(instrs, &EvalOptions::default(), false);
+    if let Exit::Looped(_) = profile.exit {
+        let block_idx = basic_block_map[profile.exit_ip];
+        println!(
+            "eval loops at ip {} (block {}): loop {:?}, scc {:?}",
+            profile.exit_ip,
+            block_idx,
+            analysis.innermost_loop(block_idx),
+            analysis.scc_map[block_idx],
+        );
+    }
+
+    let num_flips = instrs
+        .iter()
+        .filter(|instr| instr.is_jmp() || instr.is_nop())
+        .count();
+    println!(
+        "repair candidates: {:?} (of {} jmps and nops)",
+        repair_candidates(instrs),
+        num_flips,
+    );
+
+    let repair_block_idx = basic_block_map[repair_instr_idx];
+    println!(
+        "repair at ip {} (block {}): scc {:?}",
+        repair_instr_idx, repair_block_idx, analysis.scc_map[repair_block_idx],
+    );
+}
+
+fn run_width<W: Word>(input: &str, run_opts: RunOptions) -> Result<()> {
+    let mut instrs = parse_instructions::<W>(input)?;
 
     // part 1
-    dbg!(eval(&instrs).expect_err("Part 1 should loop"));
+    report(&instrs, run_opts);
+    match dbg!(eval_run(&instrs, run_opts)) {
+        Exit::Looped(_) => (),
+        exit => return Err(anyhow!("Part 1 should loop: {:?}", exit)),
+    }
 
     // part 2
     let repair_instr_idx = dbg!(find_repair(&instrs)).expect("Should be a repair");
+    if run_opts.cfg {
+        report_cfg(&instrs, repair_instr_idx);
+    }
     instrs[repair_instr_idx].repair();
 
-    dbg!(eval(&instrs)).expect("Should terminate after repair");
+    report(&instrs, run_opts);
+    match dbg!(eval_run(&instrs, run_opts)) {
+        Exit::Terminated(_) => Ok(()),
+        exit => Err(anyhow!("Should terminate after repair: {:?}", exit)),
+    }
+}
+
+// usage: day8 <input> [--width 16|32|64|128] [--trace] [--profile] [--compiled] [--cfg]
+//                      [--step-limit N] [--revisits]
+pub fn run(args: &[&str]) -> Result<()> {
+    let input = fs::read_to_string(args[0]).context("Failed to read file")?;
+
+    let mut run_opts = RunOptions {
+        width: 64,
+        trace: false,
+        profile: false,
+        compiled: false,
+        cfg: false,
+        report_opts: EvalOptions::default(),
+    };
+    let mut step_limit = None;
+    let mut revisits = false;
+
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        match *flag {
+            "--width" => {
+                let width = flags.next().context("Missing width")?;
+                run_opts.width = width.parse::<u32>().context("Invalid width")?;
+            }
+            "--trace" => run_opts.trace = true,
+            "--profile" => run_opts.profile = true,
+            "--compiled" => run_opts.compiled = true,
+            "--cfg" => run_opts.cfg = true,
+            "--step-limit" => {
+                let limit = flags.next().context("Missing step limit")?;
+                step_limit = Some(limit.parse::<usize>().context("Invalid step limit")?);
+            }
+            "--revisits" => revisits = true,
+            _ => return Err(anyhow!("unexpected arg: '{}'", flag)),
+        }
+    }
 
-    Ok(())
+    run_opts.report_opts = match (revisits, step_limit) {
+        (false, step_limit) => EvalOptions::StopOnLoop { step_limit },
+        (true, Some(step_limit)) => EvalOptions::AllowRevisits { step_limit },
+        // A looping program would never stop.
+        (true, None) => return Err(anyhow!("--revisits requires --step-limit")),
+    };
+
+    match run_opts.width {
+        16 => run_width::<i16>(&input, run_opts),
+        32 => run_width::<i32>(&input, run_opts),
+        64 => run_width::<i64>(&input, run_opts),
+        128 => run_width::<i128>(&input, run_opts),
+        width => Err(anyhow!("unsupported width: {}", width)),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use petgraph::visit::EdgeRef;
+    use crate::util::Rng;
 
     // Test on a small sample program
     #[test]
@@ -414,8 +1147,8 @@ mod test {
             jmp +1\
         ";
 
-        let mut instrs = parse_instructions(program);
-        assert_eq!(eval(&instrs), Err(5));
+        let mut instrs = parse_instructions::<i64>(program).unwrap();
+        assert_eq!(eval(&instrs), Exit::Looped(5));
 
         let leaders = leaders(&instrs, false);
 
@@ -441,12 +1174,13 @@ mod test {
 
         assert_eq!(&edges[..], &[(0, 1), (1, 4), (2, 1), (3, 4), (4, 2)][..]);
 
-        let source_connectivity = source_connectivity(&basic_block_graph);
-        let mut src_conn_idxs = source_connectivity.ones().collect::<Vec<_>>();
-        src_conn_idxs.sort_unstable();
-        assert_eq!(&src_conn_idxs[..], &[0, 1, 2, 4][..]);
+        let reachable_idxs = reachable_blocks(&basic_block_graph)
+            .ones()
+            .collect::<Vec<_>>();
+        assert_eq!(&reachable_idxs[..], &[0, 1, 2, 4][..]);
 
-        let terminal_connectivity = terminal_connectivity(&basic_block_graph);
+        let terminal_connectivity =
+            terminal_connectivity(&instrs, &basic_blocks, &basic_block_graph);
         let term_conn_idxs = terminal_connectivity.ones().collect::<Vec<_>>();
         assert_eq!(&term_conn_idxs[..], &[5][..]);
 
@@ -454,6 +1188,245 @@ mod test {
         assert_eq!(repair_instr, Some(7));
 
         instrs[repair_instr.unwrap()].repair();
-        assert_eq!(eval(&instrs), Ok(2));
+        assert_eq!(eval(&instrs), Exit::Terminated(2));
+    }
+
+    #[test]
+    fn test_traps() {
+        // Jumping exactly one past the end terminates, anything else traps.
+        let instrs = parse_instructions::<i64>("acc +1\njmp +2\nacc +1").unwrap();
+        assert_eq!(eval(&instrs), Exit::Terminated(1));
+        let instrs = parse_instructions::<i64>("acc +1\njmp +3\nacc +1").unwrap();
+        assert_eq!(eval(&instrs), Exit::Trap(Trap::OutOfBounds { ip: 1 }, 1));
+        let instrs = parse_instructions::<i64>("acc +1\njmp -2").unwrap();
+        assert_eq!(eval(&instrs), Exit::Trap(Trap::OutOfBounds { ip: 1 }, 1));
+
+        // Operands beyond i16 parse with wider words, and narrow accs trap.
+        let program = "acc +30000\nacc +30000\nacc +100000";
+        assert_eq!(
+            eval(&parse_instructions::<i64>(program).unwrap()),
+            Exit::Terminated(160_000)
+        );
+        let instrs = parse_instructions::<i16>("acc +30000\nacc +30000").unwrap();
+        assert_eq!(eval(&instrs), Exit::Trap(Trap::Overflow { ip: 1 }, 30000));
+
+        // Operands that don't fit the word type are parse errors, not panics.
+        assert!(parse_instructions::<i16>("acc +1\nacc +40000").is_err());
+        assert!(parse_instructions::<i64>("acc +1\nadd +1").is_err());
+        assert!(parse_instructions::<i64>("acc").is_err());
+
+        let instrs = parse_instructions::<i64>("nop +0\nacc +1\nacc +1").unwrap();
+        let opts = EvalOptions::StopOnLoop {
+            step_limit: Some(2),
+        };
+        assert_eq!(
+            eval_with(&instrs, &opts),
+            Exit::Trap(Trap::StepLimit { steps: 2 }, 1)
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        let program = "\
+            nop +0\n\
+            acc +1\n\
+            jmp +4\n\
+            acc +3\n\
+            jmp -3\n\
+            acc -99\n\
+            acc +1\n\
+            jmp -4\n\
+            acc +6\
+        ";
+        let instrs = parse_instructions::<i64>(program).unwrap();
+
+        let profile = Profile::new(&instrs, &EvalOptions::default(), true);
+        assert_eq!(profile.exit, Exit::Looped(5));
+        assert_eq!(profile.exit_ip, 1);
+        assert_eq!(&profile.hits[..], &[1, 1, 1, 1, 1, 0, 1, 1, 0][..]);
+
+        let trace = profile.trace.as_ref().unwrap();
+        let ips = trace.iter().map(|step| step.ip).collect::<Vec<_>>();
+        assert_eq!(&ips[..], &[0, 1, 2, 6, 7, 3, 4][..]);
+        assert_eq!(
+            trace[5],
+            Step {
+                ip: 3,
+                acc_before: 2,
+                acc_after: 5
+            }
+        );
+
+        let leaders = leaders(&instrs, false);
+        let indices = leaders.ones().collect::<Vec<_>>();
+        let basic_blocks = basic_blocks(&indices, instrs.len()).collect::<Vec<_>>();
+        let block_coverage = profile.block_coverage(&basic_blocks);
+        assert_eq!(
+            &block_coverage.ones().collect::<Vec<_>>()[..],
+            &[0, 1, 2, 4][..]
+        );
+
+        // Profiling the loop body past the first revisit
+        let opts = EvalOptions::AllowRevisits { step_limit: 12 };
+        let profile = Profile::new(&instrs, &opts, false);
+        assert_eq!(profile.exit, Exit::Trap(Trap::StepLimit { steps: 12 }, 10));
+        assert_eq!(&profile.hits[..], &[1, 2, 2, 2, 1, 0, 2, 2, 0][..]);
+    }
+
+    fn random_program(rng: &mut Rng, len: usize) -> String {
+        (0..len)
+            .map(|_| {
+                let op = ["acc", "jmp", "nop"][rng.below(3)];
+                let val = rng.below(17) as i64 - 8;
+                format!("{} {:+}", op, if op == "acc" { val * 4000 } else { val })
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_compiled() {
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+
+        for len in 1..200 {
+            let program = random_program(&mut rng, len);
+
+            let instrs = parse_instructions::<i64>(&program).unwrap();
+            let compiled = Compiled::new(&instrs);
+            assert_eq!(compiled.eval(), eval(&instrs), "{}", program);
+
+            // Narrow words trap on overflow partway through a block
+            let instrs = parse_instructions::<i16>(&program).unwrap();
+            let compiled = Compiled::new(&instrs);
+            assert_eq!(compiled.eval(), eval(&instrs), "{}", program);
+
+            for step_limit in 0..8 {
+                let opts = EvalOptions::AllowRevisits { step_limit };
+                assert_eq!(compiled.eval_with(&opts), eval_with(&instrs, &opts));
+            }
+        }
+    }
+
+    // Flip every jmp and nop and keep the ones that make the program terminate.
+    fn brute_force_repairs(instrs: &mut [Instr<i64>]) -> Vec<usize> {
+        (0..instrs.len())
+            .filter(|&idx| {
+                if instrs[idx].is_jmp() || instrs[idx].is_nop() {
+                    instrs[idx].repair();
+                    let exit = eval(instrs);
+                    instrs[idx].repair();
+                    matches!(exit, Exit::Terminated(_))
+                } else {
+                    false
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_repair_candidates() {
+        let program = "\
+            nop +0\n\
+            acc +1\n\
+            jmp +4\n\
+            acc +3\n\
+            jmp -3\n\
+            acc -99\n\
+            acc +1\n\
+            jmp -4\n\
+            jmp +1\
+        ";
+        let mut instrs = parse_instructions::<i64>(program).unwrap();
+        let num_flips = instrs
+            .iter()
+            .filter(|instr| instr.is_jmp() || instr.is_nop())
+            .count();
+        assert_eq!(num_flips, 5);
+        assert_eq!(&repair_candidates(&instrs)[..], &[7][..]);
+        assert_eq!(&brute_force_repairs(&mut instrs)[..], &[7][..]);
+
+        // The analysis finds exactly the flips that work, including jumps
+        // straight to the end and repairs of out-of-bounds jumps.
+        let mut rng = Rng::new(0x0008_0008_0008_0008);
+        for len in 1..200 {
+            for _ in 0..4 {
+                let program = random_program(&mut rng, len);
+                let mut instrs = parse_instructions::<i64>(&program).unwrap();
+                if let Exit::Terminated(_) = eval(&instrs) {
+                    assert!(repair_candidates(&instrs).is_empty(), "{}", program);
+                    continue;
+                }
+                assert_eq!(
+                    repair_candidates(&instrs),
+                    brute_force_repairs(&mut instrs),
+                    "{}",
+                    program
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_loop_analysis() {
+        // Every block has a single successor, so programs can't nest loops;
+        // test the nesting on a hand-built graph instead.
+        let graph = BasicBlockGraph::from_edges([(0, 1), (1, 2), (2, 2), (2, 3), (3, 1), (3, 4)]);
+        let analysis = LoopAnalysis::new(&graph);
+
+        assert_eq!(
+            &analysis.idom[..],
+            &[None, Some(0), Some(1), Some(2), Some(3)][..]
+        );
+        assert_eq!(&analysis.back_edges[..], &[(2, 2), (3, 1)][..]);
+
+        assert_eq!(analysis.loops.len(), 2);
+        assert_eq!(analysis.loops[0].header, 1);
+        assert_eq!(&analysis.loops[0].latches[..], &[3][..]);
+        assert_eq!(
+            &analysis.loops[0].body.ones().collect::<Vec<_>>()[..],
+            &[1, 2, 3][..]
+        );
+        assert_eq!(analysis.loops[0].parent, None);
+        assert_eq!(analysis.loops[1].header, 2);
+        assert_eq!(analysis.loops[1].parent, Some(0));
+        assert_eq!(analysis.loops[1].depth, 1);
+
+        assert_eq!(analysis.innermost_loop(2), Some(1));
+        assert_eq!(analysis.innermost_loop(3), Some(0));
+        assert_eq!(analysis.innermost_loop(4), None);
+
+        assert_eq!(&analysis.sccs[..], &[vec![1, 2, 3]][..]);
+        assert_eq!(
+            &analysis.scc_map[..],
+            &[None, Some(0), Some(0), Some(0), None][..]
+        );
+
+        // The sample program from test_repair loops in blocks 1 -> 4 -> 2 -> 1
+        let program = "\
+            nop +0\n\
+            acc +1\n\
+            jmp +4\n\
+            acc +3\n\
+            jmp -3\n\
+            acc -99\n\
+            acc +1\n\
+            jmp -4\n\
+            jmp +1\
+        ";
+        let instrs = parse_instructions::<i64>(program).unwrap();
+        let leaders = leaders(&instrs, false);
+        let indices = leaders.ones().collect::<Vec<_>>();
+        let basic_blocks = basic_blocks(&indices, instrs.len()).collect::<Vec<_>>();
+        let basic_block_map = basic_block_map(&basic_blocks).collect::<Vec<_>>();
+        let basic_block_graph = basic_block_graph(&instrs, &basic_blocks, &basic_block_map);
+        let analysis = LoopAnalysis::new(&basic_block_graph);
+
+        assert_eq!(&analysis.back_edges[..], &[(2, 1)][..]);
+        assert_eq!(&analysis.sccs[..], &[vec![1, 2, 4]][..]);
+
+        let profile = Profile::new(&instrs, &EvalOptions::default(), false);
+        let stuck_block_idx = basic_block_map[profile.exit_ip];
+        assert_eq!(analysis.innermost_loop(stuck_block_idx), Some(0));
+        assert_eq!(analysis.scc_map[stuck_block_idx], Some(0));
     }
 }
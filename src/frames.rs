@@ -0,0 +1,423 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+pub type Rgb = [u8; 3];
+
+// An image where each pixel is an index into a palette.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize, fill: u8) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![fill; width * height],
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: u8) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    // Blow up each pixel into a `scale` x `scale` square.
+    pub fn scaled(&self, scale: usize) -> Self {
+        let mut scaled = Self::new(self.width * scale, self.height * scale, 0);
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                scaled.pixels[y * scaled.width + x] =
+                    self.pixels[(y / scale) * self.width + x / scale];
+            }
+        }
+        scaled
+    }
+}
+
+// Write the frame as a binary PPM (P6) image.
+pub fn write_ppm<W: Write>(mut out: W, frame: &Frame, palette: &[Rgb]) -> Result<()> {
+    write!(out, "P6\n{} {}\n255\n", frame.width, frame.height)?;
+    let bytes = frame
+        .pixels
+        .iter()
+        .flat_map(|&color| palette[color as usize].iter().copied())
+        .collect::<Vec<_>>();
+    out.write_all(&bytes)?;
+    Ok(())
+}
+
+const MAX_LZW_CODE: u16 = 4096;
+
+// Packs variable-width codes into bytes, least significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    num_bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, code_size: u32) {
+        self.acc |= (code as u32) << self.num_bits;
+        self.num_bits += code_size;
+        while self.num_bits >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.num_bits > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+// GIF-flavored variable-width LZW compression of palette indices.
+fn lzw_encode(min_code_size: u32, pixels: &[u8]) -> Vec<u8> {
+    let clear_code: u16 = 1 << min_code_size;
+    let end_code = clear_code + 1;
+
+    let mut writer = BitWriter {
+        bytes: Vec::new(),
+        acc: 0,
+        num_bits: 0,
+    };
+    let mut dict = HashMap::<(u16, u8), u16>::new();
+    let mut next_code = end_code + 1;
+    let mut code_size = min_code_size + 1;
+
+    writer.write(clear_code, code_size);
+
+    let mut pixels = pixels.iter().copied();
+    let mut prefix = match pixels.next() {
+        Some(pixel) => pixel as u16,
+        None => {
+            writer.write(end_code, code_size);
+            return writer.finish();
+        }
+    };
+
+    for pixel in pixels {
+        if let Some(&code) = dict.get(&(prefix, pixel)) {
+            prefix = code;
+            continue;
+        }
+
+        writer.write(prefix, code_size);
+        // The decoder is always one dictionary entry behind us, so widen the
+        // codes only once it would have caught up.
+        if next_code >= (1 << code_size) && code_size < 12 {
+            code_size += 1;
+        }
+
+        if next_code < MAX_LZW_CODE {
+            dict.insert((prefix, pixel), next_code);
+            next_code += 1;
+        } else {
+            writer.write(clear_code, code_size);
+            dict.clear();
+            next_code = end_code + 1;
+            code_size = min_code_size + 1;
+        }
+
+        prefix = pixel as u16;
+    }
+
+    writer.write(prefix, code_size);
+    if next_code >= (1 << code_size) && code_size < 12 {
+        code_size += 1;
+    }
+    writer.write(end_code, code_size);
+    writer.finish()
+}
+
+// Writes an animated, infinitely looping GIF one frame at a time.
+pub struct GifWriter<W: Write> {
+    out: W,
+    width: usize,
+    height: usize,
+    // log2 of the color table size
+    depth: u32,
+    // Delay between frames, in hundredths of a second.
+    delay_cs: u16,
+}
+
+impl<W: Write> GifWriter<W> {
+    pub fn new(
+        mut out: W,
+        width: usize,
+        height: usize,
+        palette: &[Rgb],
+        delay_cs: u16,
+    ) -> Result<Self> {
+        if width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(anyhow!("frame too large for gif: {}x{}", width, height));
+        }
+        if palette.len() > 256 {
+            return Err(anyhow!("too many colors for gif: {}", palette.len()));
+        }
+
+        // The color table size must be a power of two, at least 4 since LZW
+        // codes are at least 2 bits.
+        let depth = (palette.len().max(4) as u32)
+            .next_power_of_two()
+            .trailing_zeros();
+
+        out.write_all(b"GIF89a")?;
+
+        // Logical screen descriptor with a global color table
+        out.write_all(&(width as u16).to_le_bytes())?;
+        out.write_all(&(height as u16).to_le_bytes())?;
+        let packed = 0x80 | ((depth - 1) << 4) as u8 | (depth - 1) as u8;
+        out.write_all(&[packed, 0, 0])?;
+
+        for idx in 0..(1 << depth) {
+            out.write_all(palette.get(idx).unwrap_or(&[0, 0, 0]))?;
+        }
+
+        // Netscape application extension: loop forever
+        out.write_all(&[0x21, 0xFF, 0x0B])?;
+        out.write_all(b"NETSCAPE2.0")?;
+        out.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+
+        Ok(Self {
+            out,
+            width,
+            height,
+            depth,
+            delay_cs,
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(anyhow!(
+                "frame size changed: {}x{} -> {}x{}",
+                self.width,
+                self.height,
+                frame.width,
+                frame.height
+            ));
+        }
+
+        // Graphic control extension: frame delay
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x00])?;
+        self.out.write_all(&self.delay_cs.to_le_bytes())?;
+        self.out.write_all(&[0x00, 0x00])?;
+
+        // Image descriptor covering the whole screen, no local color table
+        self.out.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.out.write_all(&(self.width as u16).to_le_bytes())?;
+        self.out.write_all(&(self.height as u16).to_le_bytes())?;
+        self.out.write_all(&[0x00])?;
+
+        // LZW data, split into sub-blocks of at most 255 bytes
+        let min_code_size = self.depth.max(2);
+        self.out.write_all(&[min_code_size as u8])?;
+        for block in lzw_encode(min_code_size, &frame.pixels).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0x00])?;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Records simulation frames as numbered PPM images in a directory, and
+// optionally as a single animated GIF.
+pub struct FrameRecorder {
+    dir: PathBuf,
+    name: String,
+    palette: Vec<Rgb>,
+    scale: usize,
+    num_frames: usize,
+    make_gif: bool,
+    gif: Option<GifWriter<BufWriter<File>>>,
+}
+
+impl FrameRecorder {
+    pub fn new(
+        dir: &str,
+        name: &str,
+        palette: &[Rgb],
+        scale: usize,
+        make_gif: bool,
+    ) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).context("Failed to create frames directory")?;
+
+        Ok(Self {
+            dir,
+            name: name.to_string(),
+            palette: palette.to_vec(),
+            scale: scale.max(1),
+            num_frames: 0,
+            make_gif,
+            gif: None,
+        })
+    }
+
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        let frame = frame.scaled(self.scale);
+
+        let path = self
+            .dir
+            .join(format!("{}_{:04}.ppm", self.name, self.num_frames));
+        let file = File::create(&path).context("Failed to create frame file")?;
+        write_ppm(BufWriter::new(file), &frame, &self.palette)?;
+
+        if self.make_gif {
+            if self.gif.is_none() {
+                let path = self.dir.join(format!("{}.gif", self.name));
+                let file = File::create(&path).context("Failed to create gif file")?;
+                let gif = GifWriter::new(
+                    BufWriter::new(file),
+                    frame.width,
+                    frame.height,
+                    &self.palette,
+                    10,
+                )?;
+                self.gif = Some(gif);
+            }
+            self.gif.as_mut().unwrap().write_frame(&frame)?;
+        }
+
+        self.num_frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if let Some(gif) = self.gif {
+            gif.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::Rng;
+
+    // Straightforward GIF LZW decoder, to check the encoder round-trips.
+    fn lzw_decode(min_code_size: u32, bytes: &[u8]) -> Vec<u8> {
+        let clear_code = 1_usize << min_code_size;
+        let end_code = clear_code + 1;
+
+        let mut bit_pos = 0;
+        let mut read = |code_size: u32| {
+            let mut code = 0;
+            for i in 0..code_size as usize {
+                let bit = (bytes[(bit_pos + i) / 8] >> ((bit_pos + i) % 8)) & 1;
+                code |= (bit as usize) << i;
+            }
+            bit_pos += code_size as usize;
+            code
+        };
+
+        let mut out = Vec::new();
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut code_size = min_code_size + 1;
+        let mut prev: Option<usize> = None;
+
+        loop {
+            let code = read(code_size);
+            if code == clear_code {
+                table = (0..clear_code).map(|c| vec![c as u8]).collect();
+                table.push(Vec::new());
+                table.push(Vec::new());
+                code_size = min_code_size + 1;
+                prev = None;
+                continue;
+            }
+            if code == end_code {
+                return out;
+            }
+
+            let entry = match prev {
+                None => table[code].clone(),
+                Some(prev) => {
+                    let entry = if code < table.len() {
+                        table[code].clone()
+                    } else {
+                        let mut entry = table[prev].clone();
+                        entry.push(table[prev][0]);
+                        entry
+                    };
+                    if table.len() < 4096 {
+                        let mut new_entry = table[prev].clone();
+                        new_entry.push(entry[0]);
+                        table.push(new_entry);
+                    }
+                    entry
+                }
+            };
+            if table.len() == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+
+            out.extend_from_slice(&entry);
+            prev = Some(code);
+        }
+    }
+
+    #[test]
+    fn test_lzw_roundtrip() {
+        let mut rng = Rng::new(34);
+        for &(len, num_colors) in &[
+            (0, 4),
+            (1, 4),
+            (17, 2),
+            (1000, 4),
+            (50_000, 4),
+            (50_000, 256),
+        ] {
+            let pixels = (0..len)
+                .map(|i| {
+                    // Mix long runs (dictionary growth) with noise
+                    if i % 7 < 4 {
+                        (i / 300 % num_colors) as u8
+                    } else {
+                        rng.below(num_colors) as u8
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let min_code_size = (num_colors.max(4) as u32).trailing_zeros();
+            let encoded = lzw_encode(min_code_size, &pixels);
+            assert_eq!(lzw_decode(min_code_size, &encoded), pixels);
+        }
+    }
+
+    #[test]
+    fn test_ppm() {
+        let mut frame = Frame::new(2, 1, 0);
+        frame.set(1, 0, 1);
+
+        let mut out = Vec::new();
+        write_ppm(&mut out, &frame.scaled(2), &[[0, 0, 0], [255, 0, 10]]).unwrap();
+
+        let mut expected = b"P6\n4 2\n255\n".to_vec();
+        for _ in 0..2 {
+            expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 255, 0, 10, 255, 0, 10]);
+        }
+        assert_eq!(out, expected);
+    }
+}
@@ -56,6 +56,9 @@ macro_rules! time {
     }};
 }
 
+mod animate;
+mod automaton;
+mod cycle;
 mod day1;
 mod day10;
 mod day11;
@@ -75,6 +78,7 @@ mod day6;
 mod day7;
 mod day8;
 mod day9;
+mod frames;
 mod util;
 
 fn main() -> Result<()> {